@@ -0,0 +1,226 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+    time::{Instant, sleep_until, timeout},
+};
+
+use crate::tracker::{AddressFamily, Event, Peer, PeerInfo, TrackerRequest, TrackerTiers, backoff};
+
+/*
+ * Background task keeping the trackers informed: `started` at launch, a re-announce every
+ * `interval` with fresh counters, `completed` once everything is downloaded and `stopped` on
+ * shutdown. Peers from every successful announce are handed to whoever holds the handle.
+ */
+
+/// The `stopped` announce is best effort; shutdown shouldn't hang on an unreachable tracker.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Bounds on the re-announce interval, whatever the tracker asks for: a tracker answering
+/// `interval 0` would otherwise be hammered in a tight loop.
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Transfer counters shared between the download and the announce task.
+#[derive(Debug)]
+pub struct TransferStats {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    left: AtomicU64,
+}
+
+impl TransferStats {
+    pub fn new(left: u64) -> Self {
+        Self {
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            left: AtomicU64::new(left),
+        }
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Marks `bytes` as no longer missing and returns how many still are.
+    pub fn complete_bytes(&self, bytes: u64) -> u64 {
+        let previous = self
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                Some(left.saturating_sub(bytes))
+            })
+            .unwrap_or_default();
+
+        previous.saturating_sub(bytes)
+    }
+}
+
+#[derive(Debug)]
+enum Command {
+    Completed,
+    Stop,
+}
+
+pub struct Announcer {
+    tiers: TrackerTiers,
+    request: TrackerRequest,
+    stats: Arc<TransferStats>,
+    family: AddressFamily,
+}
+
+impl Announcer {
+    pub fn new(
+        tiers: TrackerTiers,
+        request: TrackerRequest,
+        stats: Arc<TransferStats>,
+        family: AddressFamily,
+    ) -> Self {
+        Self {
+            tiers,
+            request,
+            stats,
+            family,
+        }
+    }
+
+    pub fn spawn(self) -> AnnouncerHandle {
+        let (peers_tx, peers_rx) = mpsc::channel(8);
+        let (control_tx, control_rx) = mpsc::channel(4);
+
+        AnnouncerHandle {
+            peers: peers_rx,
+            control: control_tx,
+            task: tokio::spawn(self.run(peers_tx, control_rx)),
+        }
+    }
+
+    async fn run(mut self, peers: mpsc::Sender<Vec<Peer>>, mut control: mpsc::Receiver<Command>) {
+        // Trackerless (e.g. a magnet link with only `x.pe` peers): nothing to announce to.
+        if self.tiers.is_empty() {
+            while let Some(Command::Completed) = control.recv().await {}
+            return;
+        }
+
+        let mut event = Some(Event::Started);
+        let mut last = Instant::now();
+        let mut next = last;
+        let mut min_interval = Duration::ZERO;
+        let mut failures = 0;
+
+        loop {
+            tokio::select! {
+                _ = sleep_until(next) => {}
+                command = control.recv() => match command {
+                    Some(Command::Completed) => {
+                        // Sent early, but never sooner than the tracker's `min interval` allows.
+                        event = Some(Event::Completed);
+                        next = next.min(last + min_interval);
+                        continue;
+                    }
+                    Some(Command::Stop) | None => {
+                        let _ = timeout(STOP_TIMEOUT, self.announce(Some(Event::Stopped))).await;
+                        return;
+                    }
+                },
+            }
+
+            last = Instant::now();
+
+            match self.announce(event).await {
+                Some(peer_info) => {
+                    // A failed `started` or `completed` is repeated on the next attempt.
+                    event = None;
+                    failures = 0;
+                    min_interval =
+                        Duration::from_secs(peer_info.min_interval.unwrap_or(0)).min(MAX_INTERVAL);
+                    next = last
+                        + Duration::from_secs(peer_info.interval)
+                            .max(min_interval)
+                            .clamp(MIN_INTERVAL, MAX_INTERVAL);
+
+                    // Never block on a slow consumer; it gets the next batch instead.
+                    let _ = peers.try_send(self.family.apply(peer_info.peers));
+                }
+                None => {
+                    // Every tracker failed; back off further with each consecutive round.
+                    failures += 1;
+                    next = last + backoff(failures).max(min_interval);
+                }
+            }
+        }
+    }
+
+    async fn announce(&mut self, event: Option<Event>) -> Option<PeerInfo> {
+        self.request.set_progress(
+            self.stats.uploaded(),
+            self.stats.downloaded(),
+            self.stats.left(),
+            event,
+        );
+
+        match self.tiers.announce(&self.request).await {
+            Ok(peer_info) => {
+                if let Some(warning) = &peer_info.warning_message {
+                    eprintln!("Tracker warning: {}", warning);
+                }
+
+                if peer_info.tracker_id.is_some() {
+                    self.request.set_tracker_id(peer_info.tracker_id.clone());
+                }
+
+                Some(peer_info)
+            }
+            Err(err) => {
+                eprintln!("Announce failed: {}", err);
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AnnouncerHandle {
+    peers: mpsc::Receiver<Vec<Peer>>,
+    control: mpsc::Sender<Command>,
+    task: JoinHandle<()>,
+}
+
+impl AnnouncerHandle {
+    /// Waits for the peers of the next successful announce.
+    pub async fn next_peers(&mut self) -> Option<Vec<Peer>> {
+        self.peers.recv().await
+    }
+
+    /// Tells the trackers the download finished.
+    pub async fn completed(&self) {
+        let _ = self.control.send(Command::Completed).await;
+    }
+
+    /// Sends `stopped` and waits for the task to exit.
+    pub async fn stop(self) {
+        let _ = self.control.send(Command::Stop).await;
+        let _ = self.task.await;
+    }
+}
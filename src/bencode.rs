@@ -1,14 +1,30 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+
+mod borrowed;
+mod de;
+mod dump;
+mod encoder;
+mod error;
+mod json;
+mod ser;
+mod stream;
+
+pub use borrowed::BencodeRef;
+pub use de::{from_bytes, from_ref, from_state};
+pub use error::BencodeError;
+pub use json::{BinaryEncoding, to_hex};
+pub use ser::{Serializer, to_bytes};
+pub use stream::{DecodeStatus, StreamDecoder};
 
 #[derive(Clone, Debug)]
 pub enum BencodeState {
     String(Vec<u8>, Vec<u8>),
-    Dictionary(HashMap<String, BencodeState>, Vec<u8>),
+    Dictionary(BencodedDictionary, Vec<u8>),
     List(Vec<BencodeState>, Vec<u8>),
-    Int(u64, Vec<u8>),
+    Int(i64, Vec<u8>),
 }
 
-pub type BencodedDictionary = HashMap<String, BencodeState>;
+pub type BencodedDictionary = BTreeMap<Vec<u8>, BencodeState>;
 
 impl BencodeState {
     pub fn try_into_string(&self) -> Result<String, String> {
@@ -26,13 +42,18 @@ impl BencodeState {
         }
     }
 
-    pub fn try_into_int(&self) -> Result<u64, String> {
+    pub fn try_into_int(&self) -> Result<i64, String> {
         match self {
             BencodeState::Int(value, _) => Ok(*value),
             _ => Err(String::from("Error parsing integer!")),
         }
     }
 
+    pub fn try_into_uint(&self) -> Result<u64, String> {
+        u64::try_from(self.try_into_int()?)
+            .map_err(|_| String::from("Error parsing unsigned integer!"))
+    }
+
     pub fn try_into_list(&self) -> Result<Vec<BencodeState>, String> {
         match self {
             BencodeState::List(value, _) => Ok(value.clone()),
@@ -40,7 +61,7 @@ impl BencodeState {
         }
     }
 
-    pub fn try_into_dict(&self) -> Result<(HashMap<String, BencodeState>, Vec<u8>), String> {
+    pub fn try_into_dict(&self) -> Result<(BencodedDictionary, Vec<u8>), String> {
         match self {
             BencodeState::Dictionary(value, raw) => Ok((value.clone(), raw.clone())),
             _ => Err(String::from("Error parsing dictionary")),
@@ -51,159 +72,412 @@ impl BencodeState {
 pub struct Bencode {}
 
 impl Bencode {
-    fn parse_string(slice: &Vec<u8>, offset: usize) -> (usize, Vec<u8>, Vec<u8>) {
-        let raw_length = slice
+    pub const MAX_DEPTH: usize = 64;
+
+    fn byte_at(slice: &[u8], offset: usize) -> Result<u8, BencodeError> {
+        slice
+            .get(offset)
+            .copied()
+            .ok_or(BencodeError::UnexpectedEof { offset })
+    }
+
+    fn parse_string(
+        slice: &[u8],
+        offset: usize,
+        strict: bool,
+    ) -> Result<(usize, &[u8]), BencodeError> {
+        let digits = slice
             .iter()
             .skip(offset)
-            .take_while(|&it| char::from(*it).is_numeric())
-            .collect::<Vec<&u8>>();
+            .take_while(|it| it.is_ascii_digit())
+            .count();
 
-        let mut raw: Vec<u8> = raw_length.iter().map(|&byte| byte.clone()).collect();
-        raw.push(b':');
+        let colon = offset + digits;
 
-        let length = raw_length
-            .iter()
-            .map(|&it| char::from(*it))
-            .collect::<String>();
+        match Self::byte_at(slice, colon)? {
+            b':' if digits > 0 => {}
+            _ => return Err(BencodeError::InvalidLength { offset }),
+        }
 
-        let new_offset = offset + length.len() + 1;
+        if strict && digits > 1 && slice[offset] == b'0' {
+            return Err(BencodeError::InvalidLength { offset });
+        }
 
-        let value = slice
-            .iter()
-            .skip(new_offset)
-            .take(length.parse::<usize>().unwrap())
-            .map(|v| v.clone())
-            .collect::<Vec<u8>>();
+        let length = std::str::from_utf8(&slice[offset..colon])
+            .ok()
+            .and_then(|it| it.parse::<usize>().ok())
+            .ok_or(BencodeError::InvalidLength { offset })?;
 
-        raw.extend(&value);
+        let start = colon + 1;
+        let end = start
+            .checked_add(length)
+            .filter(|&end| end <= slice.len())
+            .ok_or(BencodeError::UnexpectedEof {
+                offset: slice.len(),
+            })?;
 
-        (new_offset + value.len(), value, raw)
+        Ok((end, &slice[start..end]))
     }
 
-    fn parse_int(slice: &Vec<u8>, offset: usize) -> (usize, u64, Vec<u8>) {
-        let new_offset = offset + 1;
-        let mut raw: Vec<u8> = vec![slice[offset]];
+    fn is_valid_int(digits: &[u8]) -> bool {
+        let unsigned = digits.strip_prefix(b"-").unwrap_or(digits);
 
-        let raw_value = slice
-            .iter()
-            .skip(new_offset)
-            .take_while(|&it| *it != b'e')
-            .collect::<Vec<&u8>>();
+        match unsigned {
+            [] => false,
+            [b'0'] => unsigned.len() == digits.len(),
+            [b'0', ..] => false,
+            _ => unsigned.iter().all(|byte| byte.is_ascii_digit()),
+        }
+    }
 
-        raw.extend(raw_value.clone());
+    fn parse_int(slice: &[u8], offset: usize) -> Result<(usize, i64), BencodeError> {
+        let start = offset + 1;
 
-        let value = raw_value
+        let digits = slice
             .iter()
-            .map(|&it| char::from(*it))
-            .collect::<String>();
+            .skip(start)
+            .take_while(|&&it| it != b'e')
+            .count();
+
+        let end = start + digits;
+
+        Self::byte_at(slice, end)?;
+
+        if !Self::is_valid_int(&slice[start..end]) {
+            return Err(BencodeError::InvalidInteger { offset });
+        }
+
+        let value = std::str::from_utf8(&slice[start..end])
+            .ok()
+            .and_then(|it| it.parse::<i64>().ok())
+            .ok_or(BencodeError::InvalidInteger { offset })?;
+
+        Ok((end + 1, value))
+    }
+
+    fn parse_value<'a>(
+        slice: &'a [u8],
+        offset: usize,
+        depth: usize,
+        strict: bool,
+    ) -> Result<(usize, BencodeRef<'a>), BencodeError> {
+        let (end, value) = match Self::byte_at(slice, offset)? {
+            b'd' => {
+                let (o, v) = Self::parse_dictionary(slice, offset, depth + 1, strict)?;
+
+                (o, BencodeRef::Dictionary(v, &slice[offset..o]))
+            }
+            b'i' => {
+                let (o, v) = Self::parse_int(slice, offset)?;
+
+                (o, BencodeRef::Int(v, &slice[offset..o]))
+            }
+            b'l' => {
+                let (o, v) = Self::parse_list(slice, offset, depth + 1, strict)?;
+
+                (o, BencodeRef::List(v, &slice[offset..o]))
+            }
+            b'0'..=b'9' => {
+                let (o, v) = Self::parse_string(slice, offset, strict)?;
 
-        raw.push(b'e');
+                (o, BencodeRef::String(v, &slice[offset..o]))
+            }
+            byte => return Err(BencodeError::UnexpectedByte { byte, offset }),
+        };
 
-        (
-            new_offset + value.len() + 1,
-            value.parse::<u64>().unwrap(),
-            raw,
-        )
+        Ok((end, value))
     }
 
-    fn parse_list(slice: &Vec<u8>, offset: usize) -> (usize, Vec<BencodeState>, Vec<u8>) {
-        let mut list: Vec<BencodeState> = vec![];
-        let mut raw: Vec<u8> = vec![slice[offset]];
+    fn parse_list<'a>(
+        slice: &'a [u8],
+        offset: usize,
+        depth: usize,
+        strict: bool,
+    ) -> Result<(usize, Vec<BencodeRef<'a>>), BencodeError> {
+        if depth > Self::MAX_DEPTH {
+            return Err(BencodeError::NestingTooDeep { offset });
+        }
 
+        let mut list: Vec<BencodeRef> = vec![];
         let mut new_offset = offset + 1;
 
         loop {
-            if slice[new_offset] == b'e' {
-                raw.push(slice[new_offset]);
+            if Self::byte_at(slice, new_offset)? == b'e' {
                 new_offset += 1;
                 break;
             }
 
-            let (value_end, value, raw_value) = match slice[new_offset] {
-                b'd' => {
-                    let (o, v, r) = Self::parse_dictionary(slice, new_offset);
+            let (value_end, value) = Self::parse_value(slice, new_offset, depth, strict)?;
 
-                    (o, BencodeState::Dictionary(v, r.clone()), r)
-                }
-                b'i' => {
-                    let (o, v, r) = Self::parse_int(slice, new_offset);
+            new_offset = value_end;
+            list.push(value);
+        }
 
-                    (o, BencodeState::Int(v, r.clone()), r)
-                }
-                b'l' => {
-                    let (o, v, r) = Self::parse_list(slice, new_offset);
+        Ok((new_offset, list))
+    }
 
-                    (o, BencodeState::List(v, r.clone()), r)
+    fn parse_dictionary<'a>(
+        slice: &'a [u8],
+        offset: usize,
+        depth: usize,
+        strict: bool,
+    ) -> Result<(usize, BTreeMap<&'a [u8], BencodeRef<'a>>), BencodeError> {
+        if depth > Self::MAX_DEPTH {
+            return Err(BencodeError::NestingTooDeep { offset });
+        }
+
+        let mut dictionary: BTreeMap<&[u8], BencodeRef> = BTreeMap::new();
+        let mut new_offset = offset + 1;
+
+        loop {
+            match Self::byte_at(slice, new_offset)? {
+                b'e' => {
+                    new_offset += 1;
+                    break;
+                }
+                b'0'..=b'9' => {}
+                byte => {
+                    return Err(BencodeError::UnexpectedByte {
+                        byte,
+                        offset: new_offset,
+                    });
                 }
-                _ => {
-                    let (o, v, r) = Self::parse_string(slice, new_offset);
+            }
 
-                    (o, BencodeState::String(v.clone(), r.clone()), r)
+            let (key_end, key) = Self::parse_string(slice, new_offset, strict)?;
+
+            if strict {
+                match dictionary.last_key_value() {
+                    Some((last, _)) if *last == key => {
+                        return Err(BencodeError::DuplicateKey { offset: new_offset });
+                    }
+                    Some((last, _)) if *last > key => {
+                        return Err(BencodeError::UnsortedKey { offset: new_offset });
+                    }
+                    _ => {}
                 }
-            };
+            }
+
+            let (value_end, value) = Self::parse_value(slice, key_end, depth, strict)?;
 
             new_offset = value_end;
 
-            raw.extend(raw_value);
-            list.push(value);
+            dictionary.insert(key, value);
         }
 
-        (new_offset, list, raw)
+        Ok((new_offset, dictionary))
     }
 
-    fn parse_dictionary(slice: &Vec<u8>, offset: usize) -> (usize, BencodedDictionary, Vec<u8>) {
-        let mut dictionary: BencodedDictionary = HashMap::new();
-        let mut raw: Vec<u8> = vec![slice[offset]];
+    fn decode_value(slice: &[u8], strict: bool) -> Result<BencodeRef<'_>, BencodeError> {
+        let (end, value) = Self::parse_value(slice, 0, 0, strict)?;
 
-        let mut new_offset = offset + 1;
+        if end != slice.len() {
+            return Err(BencodeError::TrailingData { offset: end });
+        }
 
-        loop {
-            if slice[new_offset] == b'e' {
-                raw.push(slice[new_offset]);
-                new_offset += 1;
-                break;
-            }
+        Ok(value)
+    }
 
-            let (key_end, key, raw_key) = Self::parse_string(slice, new_offset);
+    fn decode_dictionary(slice: &[u8], strict: bool) -> Result<BencodedDictionary, BencodeError> {
+        match Self::decode_value(slice, strict)? {
+            BencodeRef::Dictionary(dictionary, _) => Ok(dictionary
+                .iter()
+                .map(|(key, value)| (key.to_vec(), value.to_state()))
+                .collect()),
+            _ => Err(BencodeError::UnexpectedByte {
+                byte: slice[0],
+                offset: 0,
+            }),
+        }
+    }
 
-            let (value_end, value, raw_value) = match slice[key_end] {
-                b'd' => {
-                    let (o, v, r) = Self::parse_dictionary(slice, key_end);
+    pub fn decode(slice: &[u8]) -> Result<BencodeState, BencodeError> {
+        Ok(Self::decode_value(slice, false)?.to_state())
+    }
 
-                    (o, BencodeState::Dictionary(v, r.clone()), r)
-                }
-                b'i' => {
-                    let (o, v, r) = Self::parse_int(slice, key_end);
+    /// Decodes while enforcing the canonical form from BEP 3: dictionary keys must be sorted
+    /// and unique, and string lengths must not have leading zeros.
+    pub fn decode_strict(slice: &[u8]) -> Result<BencodeState, BencodeError> {
+        Ok(Self::decode_value(slice, true)?.to_state())
+    }
 
-                    (o, BencodeState::Int(v, r.clone()), r)
-                }
-                b'l' => {
-                    let (o, v, r) = Self::parse_list(slice, key_end);
+    /// Decodes without copying: every node borrows from `slice`, including its raw span.
+    pub fn decode_ref(slice: &[u8]) -> Result<BencodeRef<'_>, BencodeError> {
+        Self::decode_value(slice, false)
+    }
 
-                    (o, BencodeState::List(v, r.clone()), r)
-                }
-                _ => {
-                    let (o, v, r) = Self::parse_string(slice, key_end);
+    pub fn decode_ref_strict(slice: &[u8]) -> Result<BencodeRef<'_>, BencodeError> {
+        Self::decode_value(slice, true)
+    }
 
-                    (o, BencodeState::String(v.clone(), r.clone()), r)
-                }
-            };
+    pub fn decode_dict(slice: Vec<u8>) -> Result<BencodedDictionary, BencodeError> {
+        Self::decode_dictionary(&slice, false)
+    }
 
-            new_offset = value_end;
+    pub fn decode_dict_strict(slice: Vec<u8>) -> Result<BencodedDictionary, BencodeError> {
+        Self::decode_dictionary(&slice, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_err(input: &[u8]) -> BencodeError {
+        Bencode::decode(input).unwrap_err()
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        assert_eq!(decode_err(b""), BencodeError::UnexpectedEof { offset: 0 });
+        assert_eq!(
+            decode_err(b"i12"),
+            BencodeError::UnexpectedEof { offset: 3 }
+        );
+        assert_eq!(
+            decode_err(b"5:abc"),
+            BencodeError::UnexpectedEof { offset: 5 }
+        );
+        assert_eq!(decode_err(b"l"), BencodeError::UnexpectedEof { offset: 1 });
+        assert_eq!(
+            decode_err(b"li1e"),
+            BencodeError::UnexpectedEof { offset: 4 }
+        );
+        assert_eq!(
+            decode_err(b"d1:a"),
+            BencodeError::UnexpectedEof { offset: 4 }
+        );
+        assert_eq!(
+            decode_err(b"d1:ai1e"),
+            BencodeError::UnexpectedEof { offset: 7 }
+        );
+    }
 
-            raw.extend(raw_key);
-            raw.extend(raw_value);
+    #[test]
+    fn rejects_every_prefix_of_a_value() {
+        let input = b"d8:announce3:url4:infod6:lengthi42e5:filesld4:pathl1:aeeee4:listli-7e0:ee";
 
-            dictionary.insert(
-                key.iter().map(|&it| char::from(it)).collect::<String>(),
-                value,
+        assert!(Bencode::decode(input).is_ok());
+
+        for end in 0..input.len() {
+            assert!(
+                Bencode::decode(&input[..end]).is_err(),
+                "prefix of {} bytes",
+                end
             );
         }
+    }
+
+    #[test]
+    fn rejects_malformed_integers() {
+        for input in [
+            &b"ie"[..],
+            b"i-e",
+            b"i-0e",
+            b"i03e",
+            b"i99999999999999999999e",
+        ] {
+            assert_eq!(
+                decode_err(input),
+                BencodeError::InvalidInteger { offset: 0 }
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_lengths() {
+        assert_eq!(
+            decode_err(b"3x:abc"),
+            BencodeError::InvalidLength { offset: 0 }
+        );
+        assert_eq!(
+            decode_err(b"99999999999999999999:a"),
+            BencodeError::InvalidLength { offset: 0 }
+        );
+    }
+
+    #[test]
+    fn rejects_unexpected_bytes() {
+        assert_eq!(
+            decode_err(b"x"),
+            BencodeError::UnexpectedByte {
+                byte: b'x',
+                offset: 0
+            }
+        );
+        assert_eq!(
+            decode_err(b"e"),
+            BencodeError::UnexpectedByte {
+                byte: b'e',
+                offset: 0
+            }
+        );
+        // Dictionary keys have to be strings.
+        assert_eq!(
+            decode_err(b"di1ei2ee"),
+            BencodeError::UnexpectedByte {
+                byte: b'i',
+                offset: 1
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_trailing_data() {
+        assert_eq!(
+            decode_err(b"i1ei2e"),
+            BencodeError::TrailingData { offset: 3 }
+        );
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let input = vec![b'l'; 1000];
+
+        assert_eq!(
+            decode_err(&input),
+            BencodeError::NestingTooDeep {
+                offset: Bencode::MAX_DEPTH
+            }
+        );
+    }
 
-        (new_offset, dictionary, raw)
+    #[test]
+    fn strict_mode_requires_sorted_unique_keys() {
+        assert_eq!(
+            Bencode::decode_strict(b"d1:bi1e1:ai2ee").unwrap_err(),
+            BencodeError::UnsortedKey { offset: 7 }
+        );
+        assert_eq!(
+            Bencode::decode_strict(b"d1:bi1e2:aai2ee").unwrap_err(),
+            BencodeError::UnsortedKey { offset: 7 }
+        );
+        assert_eq!(
+            Bencode::decode_strict(b"d1:ai1e1:ai2ee").unwrap_err(),
+            BencodeError::DuplicateKey { offset: 7 }
+        );
+        // Nested dictionaries are checked too.
+        assert_eq!(
+            Bencode::decode_strict(b"d1:ad1:bi1e1:ai1eee").unwrap_err(),
+            BencodeError::UnsortedKey { offset: 11 }
+        );
+
+        // Keys compare as raw bytes, so uppercase sorts first and prefixes before extensions.
+        assert!(Bencode::decode_strict(b"d1:Bi1e1:ai1ee").is_ok());
+        assert!(Bencode::decode_strict(b"d1:ai1e2:aai2ee").is_ok());
+
+        // Lenient decoding accepts all of these.
+        assert!(Bencode::decode(b"d1:bi1e1:ai2ee").is_ok());
+        assert!(Bencode::decode(b"d1:ai1e1:ai2ee").is_ok());
     }
 
-    pub fn decode_dict(slice: Vec<u8>) -> BencodedDictionary {
-        Bencode::parse_dictionary(&slice, 0).1
+    #[test]
+    fn strict_mode_rejects_leading_zero_lengths() {
+        assert_eq!(
+            Bencode::decode_strict(b"03:abc").unwrap_err(),
+            BencodeError::InvalidLength { offset: 0 }
+        );
+        assert!(Bencode::decode(b"03:abc").is_ok());
+        assert!(Bencode::decode_strict(b"0:").is_ok());
     }
 }
@@ -0,0 +1,80 @@
+use std::collections::BTreeMap;
+
+use super::BencodeState;
+
+/// Borrowed counterpart of `BencodeState`: values and raw spans are slices into the decoded
+/// input, so nothing is copied until `to_state` is called.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BencodeRef<'a> {
+    String(&'a [u8], &'a [u8]),
+    Dictionary(BTreeMap<&'a [u8], BencodeRef<'a>>, &'a [u8]),
+    List(Vec<BencodeRef<'a>>, &'a [u8]),
+    Int(i64, &'a [u8]),
+}
+
+impl<'a> BencodeRef<'a> {
+    /// The exact bytes this node was decoded from, e.g. the span hashed for the info hash.
+    pub fn raw(&self) -> &'a [u8] {
+        match self {
+            BencodeRef::String(_, raw)
+            | BencodeRef::Dictionary(_, raw)
+            | BencodeRef::List(_, raw)
+            | BencodeRef::Int(_, raw) => raw,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self {
+            BencodeRef::String(value, _) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        self.as_bytes()
+            .and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            BencodeRef::Int(value, _) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[BencodeRef<'a>]> {
+        match self {
+            BencodeRef::List(value, _) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<&'a [u8], BencodeRef<'a>>> {
+        match self {
+            BencodeRef::Dictionary(value, _) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Looks up `key` when this node is a dictionary.
+    pub fn get(&self, key: &[u8]) -> Option<&BencodeRef<'a>> {
+        self.as_dict().and_then(|dictionary| dictionary.get(key))
+    }
+
+    pub fn to_state(&self) -> BencodeState {
+        match self {
+            BencodeRef::String(value, raw) => BencodeState::String(value.to_vec(), raw.to_vec()),
+            BencodeRef::Int(value, raw) => BencodeState::Int(*value, raw.to_vec()),
+            BencodeRef::List(value, raw) => {
+                BencodeState::List(value.iter().map(|it| it.to_state()).collect(), raw.to_vec())
+            }
+            BencodeRef::Dictionary(value, raw) => BencodeState::Dictionary(
+                value
+                    .iter()
+                    .map(|(key, value)| (key.to_vec(), value.to_state()))
+                    .collect(),
+                raw.to_vec(),
+            ),
+        }
+    }
+}
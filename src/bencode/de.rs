@@ -0,0 +1,277 @@
+use serde::de::{
+    self, Deserialize, DeserializeOwned, IntoDeserializer, Visitor,
+    value::{BorrowedBytesDeserializer, MapDeserializer, SeqDeserializer},
+};
+
+use super::{Bencode, BencodeError, BencodeRef, BencodeState};
+
+/// Deserializes straight from the input buffer, so `&[u8]`/`&str` fields can borrow from it.
+pub fn from_bytes<'de, T: Deserialize<'de>>(slice: &'de [u8]) -> Result<T, BencodeError> {
+    T::deserialize(Bencode::decode_ref(slice)?)
+}
+
+pub fn from_ref<'de, T: Deserialize<'de>>(value: BencodeRef<'de>) -> Result<T, BencodeError> {
+    T::deserialize(value)
+}
+
+/// Goes through the encoded form, so owned values share the `BencodeRef` deserializer.
+pub fn from_state<T: DeserializeOwned>(value: BencodeState) -> Result<T, BencodeError> {
+    from_bytes(&value.encode())
+}
+
+impl<'de> IntoDeserializer<'de, BencodeError> for BencodeRef<'de> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+impl<'de> de::Deserializer<'de> for BencodeRef<'de> {
+    type Error = BencodeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        match self {
+            BencodeRef::String(value, _) => visitor.visit_borrowed_bytes(value),
+            BencodeRef::Int(value, _) => visitor.visit_i64(value),
+            BencodeRef::List(value, _) => {
+                let mut seq = SeqDeserializer::new(value.into_iter());
+                let result = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+
+                Ok(result)
+            }
+            BencodeRef::Dictionary(value, _) => {
+                let mut map = MapDeserializer::new(
+                    value
+                        .into_iter()
+                        .map(|(key, value)| (BorrowedBytesDeserializer::new(key), value)),
+                );
+                let result = visitor.visit_map(&mut map)?;
+                map.end()?;
+
+                Ok(result)
+            }
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        match self {
+            BencodeRef::Int(value, _) => visitor.visit_bool(value != 0),
+            other => other.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        match self {
+            BencodeRef::String(value, _) => match std::str::from_utf8(value) {
+                Ok(value) => visitor.visit_borrowed_str(value),
+                Err(_) => visitor.visit_borrowed_bytes(value),
+            },
+            other => other.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        match self {
+            variant @ BencodeRef::String(..) => visitor.visit_enum(variant),
+            BencodeRef::Dictionary(value, _) if value.len() == 1 => {
+                let (variant, value) = value.into_iter().next().unwrap();
+
+                visitor.visit_enum(EnumDeserializer { variant, value })
+            }
+            _ => Err(BencodeError::Custom(String::from(
+                "expected a string or single-key dictionary for enum",
+            ))),
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char bytes byte_buf unit unit_struct
+        seq tuple tuple_struct map struct identifier
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for BencodeRef<'de> {
+    type Error = BencodeError;
+    type Variant = UnitVariant;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, UnitVariant), BencodeError> {
+        Ok((seed.deserialize(self)?, UnitVariant))
+    }
+}
+
+pub struct UnitVariant;
+
+impl<'de> de::VariantAccess<'de> for UnitVariant {
+    type Error = BencodeError;
+
+    fn unit_variant(self) -> Result<(), BencodeError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(
+        self,
+        _seed: T,
+    ) -> Result<T::Value, BencodeError> {
+        Err(BencodeError::Custom(String::from(
+            "expected a dictionary for newtype variant",
+        )))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        _visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        Err(BencodeError::Custom(String::from(
+            "expected a dictionary for tuple variant",
+        )))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        Err(BencodeError::Custom(String::from(
+            "expected a dictionary for struct variant",
+        )))
+    }
+}
+
+pub struct EnumDeserializer<'de> {
+    variant: &'de [u8],
+    value: BencodeRef<'de>,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumDeserializer<'de> {
+    type Error = BencodeError;
+    type Variant = BencodeRef<'de>;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, BencodeRef<'de>), BencodeError> {
+        let variant =
+            seed.deserialize(BorrowedBytesDeserializer::<BencodeError>::new(self.variant))?;
+
+        Ok((variant, self.value))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for BencodeRef<'de> {
+    type Error = BencodeError;
+
+    fn unit_variant(self) -> Result<(), BencodeError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, BencodeError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    enum Shape {
+        Point,
+        Circle(u32),
+        Rect { width: u32, height: u32 },
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Drawing {
+        name: String,
+        shapes: Vec<Shape>,
+        visible: bool,
+        tag: Option<Vec<u8>>,
+    }
+
+    #[test]
+    fn deserializes_owned_and_borrowed_values_alike() {
+        let input = b"d4:name3:abc6:shapesl5:Pointd6:Circlei3eed4:Rectd6:heighti2e5:widthi1eeee7:visiblei1ee";
+        let expected = Drawing {
+            name: String::from("abc"),
+            shapes: vec![
+                Shape::Point,
+                Shape::Circle(3),
+                Shape::Rect {
+                    width: 1,
+                    height: 2,
+                },
+            ],
+            visible: true,
+            tag: None,
+        };
+
+        assert_eq!(from_bytes::<Drawing>(input).unwrap(), expected);
+        assert_eq!(
+            from_state::<Drawing>(Bencode::decode(input).unwrap()).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn rejects_mismatched_types() {
+        assert!(from_bytes::<u32>(b"3:abc").is_err());
+        assert!(from_bytes::<String>(b"i1e").is_err());
+        assert!(from_bytes::<Shape>(b"d5:Pointi1e6:Circlei2ee").is_err());
+        assert!(from_state::<Vec<u8>>(Bencode::decode(b"i1e").unwrap()).is_err());
+    }
+}
@@ -0,0 +1,95 @@
+use std::fmt::Write;
+
+use super::{Bencode, BencodeError, BencodeRef, json::to_hex};
+
+const PREVIEW_LENGTH: usize = 64;
+
+fn preview(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(value) if value.chars().all(|it| !it.is_control()) => {
+            if value.chars().count() > PREVIEW_LENGTH {
+                format!(
+                    "{:?}...",
+                    value.chars().take(PREVIEW_LENGTH).collect::<String>()
+                )
+            } else {
+                format!("{:?}", value)
+            }
+        }
+        _ => {
+            let shown = &bytes[..bytes.len().min(PREVIEW_LENGTH / 2)];
+            let ellipsis = if shown.len() < bytes.len() { "..." } else { "" };
+
+            format!("<{} bytes: {}{}>", bytes.len(), to_hex(shown), ellipsis)
+        }
+    }
+}
+
+fn write_node(
+    out: &mut String,
+    input: &[u8],
+    node: &BencodeRef,
+    key: Option<&[u8]>,
+    indent: usize,
+) {
+    // Every node borrows from `input`, so its offset is just the distance between the pointers.
+    let offset = node.raw().as_ptr() as usize - input.as_ptr() as usize;
+    let label = key
+        .map(|key| format!("{}: ", preview(key)))
+        .unwrap_or_default();
+    let padding = "  ".repeat(indent);
+
+    let _ = match node {
+        BencodeRef::String(value, _) => {
+            writeln!(out, "{:>8}  {}{}{}", offset, padding, label, preview(value))
+        }
+        BencodeRef::Int(value, _) => writeln!(out, "{:>8}  {}{}{}", offset, padding, label, value),
+        BencodeRef::List(value, _) => {
+            writeln!(
+                out,
+                "{:>8}  {}{}list ({} items)",
+                offset,
+                padding,
+                label,
+                value.len()
+            )
+        }
+        BencodeRef::Dictionary(value, _) => {
+            writeln!(
+                out,
+                "{:>8}  {}{}dict ({} entries)",
+                offset,
+                padding,
+                label,
+                value.len()
+            )
+        }
+    };
+
+    match node {
+        BencodeRef::List(value, _) => {
+            for item in value {
+                write_node(out, input, item, None, indent + 1);
+            }
+        }
+        BencodeRef::Dictionary(value, _) => {
+            for (key, item) in value {
+                write_node(out, input, item, Some(key), indent + 1);
+            }
+        }
+        _ => {}
+    }
+}
+
+impl Bencode {
+    /// Renders any bencoded value as an indented tree, one node per line prefixed with the
+    /// byte offset it starts at.
+    pub fn dump(input: &[u8]) -> Result<String, BencodeError> {
+        let value = Self::decode_ref(input)?;
+        let mut out = String::new();
+
+        write_node(&mut out, input, &value, None, 0);
+
+        Ok(out)
+    }
+}
@@ -0,0 +1,148 @@
+use super::{Bencode, BencodeState, BencodedDictionary};
+
+impl Bencode {
+    fn encode_string(value: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(value.len().to_string().as_bytes());
+        out.push(b':');
+        out.extend_from_slice(value);
+    }
+
+    fn encode_int(value: i64, out: &mut Vec<u8>) {
+        out.push(b'i');
+        out.extend_from_slice(value.to_string().as_bytes());
+        out.push(b'e');
+    }
+
+    fn encode_list(list: &[BencodeState], out: &mut Vec<u8>) {
+        out.push(b'l');
+
+        for value in list {
+            Self::encode_value(value, out);
+        }
+
+        out.push(b'e');
+    }
+
+    fn encode_dictionary(dictionary: &BencodedDictionary, out: &mut Vec<u8>) {
+        out.push(b'd');
+
+        // BencodedDictionary is ordered by raw key bytes, which is exactly the canonical order.
+        for (key, value) in dictionary {
+            Self::encode_string(key, out);
+            Self::encode_value(value, out);
+        }
+
+        out.push(b'e');
+    }
+
+    fn encode_value(value: &BencodeState, out: &mut Vec<u8>) {
+        match value {
+            BencodeState::String(value, _) => Self::encode_string(value, out),
+            BencodeState::Int(value, _) => Self::encode_int(*value, out),
+            BencodeState::List(value, _) => Self::encode_list(value, out),
+            BencodeState::Dictionary(value, _) => Self::encode_dictionary(value, out),
+        }
+    }
+
+    pub fn encode(value: &BencodeState) -> Vec<u8> {
+        let mut out = vec![];
+        Self::encode_value(value, &mut out);
+
+        out
+    }
+
+    pub fn encode_dict(dictionary: &BencodedDictionary) -> Vec<u8> {
+        let mut out = vec![];
+        Self::encode_dictionary(dictionary, &mut out);
+
+        out
+    }
+}
+
+impl BencodeState {
+    pub fn encode(&self) -> Vec<u8> {
+        Bencode::encode(self)
+    }
+
+    pub fn from_bytes(value: Vec<u8>) -> Self {
+        let mut raw = vec![];
+        Bencode::encode_string(&value, &mut raw);
+
+        BencodeState::String(value, raw)
+    }
+
+    pub fn from_int(value: i64) -> Self {
+        let mut raw = vec![];
+        Bencode::encode_int(value, &mut raw);
+
+        BencodeState::Int(value, raw)
+    }
+
+    pub fn from_list(value: Vec<BencodeState>) -> Self {
+        let mut raw = vec![];
+        Bencode::encode_list(&value, &mut raw);
+
+        BencodeState::List(value, raw)
+    }
+
+    pub fn from_dict(value: BencodedDictionary) -> Self {
+        let mut raw = vec![];
+        Bencode::encode_dictionary(&value, &mut raw);
+
+        BencodeState::Dictionary(value, raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_every_type() {
+        let value = BencodeState::from_dict(BencodedDictionary::from([
+            (b"int".to_vec(), BencodeState::from_int(-42)),
+            (
+                b"list".to_vec(),
+                BencodeState::from_list(vec![
+                    BencodeState::from_bytes(b"spam".to_vec()),
+                    BencodeState::from_bytes(vec![]),
+                ]),
+            ),
+            (b"bytes".to_vec(), BencodeState::from_bytes(vec![0, 255])),
+        ]));
+
+        assert_eq!(
+            value.encode(),
+            b"d5:bytes2:\x00\xff3:inti-42e4:listl4:spam0:ee"
+        );
+    }
+
+    #[test]
+    fn round_trips_canonical_input() {
+        for input in [
+            &b"i0e"[..],
+            b"i-9223372036854775808e",
+            b"0:",
+            b"le",
+            b"de",
+            b"d1:ad1:bli1ei2eee1:c3:xyze",
+        ] {
+            assert_eq!(Bencode::decode(input).unwrap().encode(), input);
+        }
+    }
+
+    #[test]
+    fn sorts_keys_by_raw_bytes() {
+        let dictionary = BencodedDictionary::from([
+            (b"b".to_vec(), BencodeState::from_int(2)),
+            (b"B".to_vec(), BencodeState::from_int(1)),
+            (b"a".to_vec(), BencodeState::from_int(3)),
+        ]);
+
+        assert_eq!(Bencode::encode_dict(&dictionary), b"d1:Bi1e1:ai3e1:bi2ee");
+        assert_eq!(
+            Bencode::decode(b"d1:bi2e1:ai3ee").unwrap().encode(),
+            b"d1:ai3e1:bi2ee"
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BencodeError {
+    UnexpectedEof {
+        offset: usize,
+    },
+    UnexpectedByte {
+        byte: u8,
+        offset: usize,
+    },
+    InvalidLength {
+        offset: usize,
+    },
+    InvalidInteger {
+        offset: usize,
+    },
+    TrailingData {
+        offset: usize,
+    },
+    NestingTooDeep {
+        offset: usize,
+    },
+    UnsortedKey {
+        offset: usize,
+    },
+    DuplicateKey {
+        offset: usize,
+    },
+    TooLarge {
+        offset: usize,
+    },
+    /// Raised by the serde layer when a value does not fit the target type.
+    Custom(String),
+}
+
+impl BencodeError {
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            Self::UnexpectedEof { offset }
+            | Self::UnexpectedByte { offset, .. }
+            | Self::InvalidLength { offset }
+            | Self::InvalidInteger { offset }
+            | Self::TrailingData { offset }
+            | Self::NestingTooDeep { offset }
+            | Self::UnsortedKey { offset }
+            | Self::DuplicateKey { offset }
+            | Self::TooLarge { offset } => Some(*offset),
+            Self::Custom(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for BencodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEof { offset } => {
+                write!(f, "unexpected end of input at byte {}", offset)
+            }
+            Self::UnexpectedByte { byte, offset } => {
+                write!(f, "unexpected byte 0x{:02x} at byte {}", byte, offset)
+            }
+            Self::InvalidLength { offset } => {
+                write!(f, "invalid string length prefix at byte {}", offset)
+            }
+            Self::InvalidInteger { offset } => write!(f, "invalid integer at byte {}", offset),
+            Self::TrailingData { offset } => write!(f, "trailing data at byte {}", offset),
+            Self::NestingTooDeep { offset } => write!(f, "nesting too deep at byte {}", offset),
+            Self::UnsortedKey { offset } => {
+                write!(f, "dictionary key out of order at byte {}", offset)
+            }
+            Self::DuplicateKey { offset } => {
+                write!(f, "duplicate dictionary key at byte {}", offset)
+            }
+            Self::TooLarge { offset } => write!(f, "input exceeds size limit at byte {}", offset),
+            Self::Custom(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for BencodeError {}
+
+impl serde::ser::Error for BencodeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::Custom(msg.to_string())
+    }
+}
+
+impl serde::de::Error for BencodeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::Custom(msg.to_string())
+    }
+}
+
+impl From<BencodeError> for String {
+    fn from(value: BencodeError) -> Self {
+        value.to_string()
+    }
+}
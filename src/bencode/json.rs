@@ -0,0 +1,173 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde_json::{Map, Number, Value};
+
+use super::{BencodeError, BencodeState, BencodedDictionary};
+
+/// How byte strings that are not valid UTF-8 are written to JSON. They are emitted as
+/// `"hex:<digits>"` or `"base64:<data>"`; UTF-8 strings that happen to start with one of
+/// those prefixes are escaped the same way, so the conversion always round-trips.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BinaryEncoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+const HEX_PREFIX: &str = "hex:";
+const BASE64_PREFIX: &str = "base64:";
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..value.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(value.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn bytes_to_json(bytes: &[u8], encoding: BinaryEncoding) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(value) if !value.starts_with(HEX_PREFIX) && !value.starts_with(BASE64_PREFIX) => {
+            value.to_string()
+        }
+        _ => match encoding {
+            BinaryEncoding::Hex => format!("{}{}", HEX_PREFIX, to_hex(bytes)),
+            BinaryEncoding::Base64 => format!("{}{}", BASE64_PREFIX, STANDARD.encode(bytes)),
+        },
+    }
+}
+
+fn bytes_from_json(value: &str) -> Result<Vec<u8>, BencodeError> {
+    if let Some(hex) = value.strip_prefix(HEX_PREFIX) {
+        return from_hex(hex).ok_or(BencodeError::Custom(format!(
+            "invalid hex string {}",
+            value
+        )));
+    }
+
+    if let Some(data) = value.strip_prefix(BASE64_PREFIX) {
+        return STANDARD
+            .decode(data)
+            .map_err(|err| BencodeError::Custom(format!("invalid base64 string: {}", err)));
+    }
+
+    Ok(value.as_bytes().to_vec())
+}
+
+impl BencodeState {
+    pub fn to_json(&self, encoding: BinaryEncoding) -> Value {
+        match self {
+            BencodeState::String(value, _) => Value::String(bytes_to_json(value, encoding)),
+            BencodeState::Int(value, _) => Value::Number(Number::from(*value)),
+            BencodeState::List(value, _) => {
+                Value::Array(value.iter().map(|it| it.to_json(encoding)).collect())
+            }
+            BencodeState::Dictionary(value, _) => Value::Object(
+                value
+                    .iter()
+                    .map(|(key, value)| (bytes_to_json(key, encoding), value.to_json(encoding)))
+                    .collect::<Map<String, Value>>(),
+            ),
+        }
+    }
+
+    /// Inverse of `to_json`. JSON booleans become `0`/`1`; floats and `null` have no bencode
+    /// representation and are rejected.
+    pub fn from_json(value: &Value) -> Result<Self, BencodeError> {
+        Ok(match value {
+            Value::String(value) => BencodeState::from_bytes(bytes_from_json(value)?),
+            Value::Bool(value) => BencodeState::from_int(*value as i64),
+            Value::Number(number) => BencodeState::from_int(number.as_i64().ok_or(
+                BencodeError::Custom(format!("{} is not a bencode integer", number)),
+            )?),
+            Value::Array(value) => BencodeState::from_list(
+                value
+                    .iter()
+                    .map(BencodeState::from_json)
+                    .collect::<Result<Vec<BencodeState>, BencodeError>>()?,
+            ),
+            Value::Object(value) => BencodeState::from_dict(
+                value
+                    .iter()
+                    .map(|(key, value)| {
+                        Ok((bytes_from_json(key)?, BencodeState::from_json(value)?))
+                    })
+                    .collect::<Result<BencodedDictionary, BencodeError>>()?,
+            ),
+            Value::Null => {
+                return Err(BencodeError::Custom(String::from(
+                    "null has no bencode representation",
+                )));
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::bencode::Bencode;
+
+    fn round_trip(input: &[u8], encoding: BinaryEncoding) -> Vec<u8> {
+        let json = Bencode::decode(input).unwrap().to_json(encoding);
+
+        BencodeState::from_json(&json).unwrap().encode()
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let input = b"d7:base64:1:z6:binary3:\x00\xff\x104:hex:2:ab4:listli-1e4:textee";
+
+        assert_eq!(round_trip(input, BinaryEncoding::Hex), input);
+        assert_eq!(round_trip(input, BinaryEncoding::Base64), input);
+    }
+
+    #[test]
+    fn escapes_binary_and_prefixed_strings() {
+        let value = Bencode::decode(b"l3:\x00\xff\x104:hex:7:base64:4:texte").unwrap();
+
+        assert_eq!(
+            value.to_json(BinaryEncoding::Hex),
+            json!(["hex:00ff10", "hex:6865783a", "hex:6261736536343a", "text"])
+        );
+        assert_eq!(
+            value.to_json(BinaryEncoding::Base64),
+            json!([
+                "base64:AP8Q",
+                "base64:aGV4Og==",
+                "base64:YmFzZTY0Og==",
+                "text"
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_unrepresentable_json() {
+        for value in [
+            json!(null),
+            json!(1.5),
+            json!(u64::MAX),
+            json!("hex:0"),
+            json!("hex:zz"),
+            json!("base64:!"),
+            json!({"key": null}),
+        ] {
+            assert!(BencodeState::from_json(&value).is_err(), "{}", value);
+        }
+
+        assert_eq!(
+            BencodeState::from_json(&json!([true, false]))
+                .unwrap()
+                .encode(),
+            b"li1ei0ee"
+        );
+    }
+}
@@ -0,0 +1,591 @@
+use serde::ser::{self, Impossible, Serialize};
+
+use super::BencodeError;
+
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, BencodeError> {
+    let mut serializer = Serializer::default();
+    value.serialize(&mut serializer)?;
+
+    Ok(serializer.out)
+}
+
+#[derive(Default)]
+pub struct Serializer {
+    out: Vec<u8>,
+    /// Set while serializing a dictionary value, which is left out when it is `None` or unit.
+    entry: bool,
+}
+
+impl Serializer {
+    fn write_int(&mut self, value: i64) {
+        self.out.push(b'i');
+        self.out.extend_from_slice(value.to_string().as_bytes());
+        self.out.push(b'e');
+    }
+
+    fn write_bytes(&mut self, value: &[u8]) {
+        self.out
+            .extend_from_slice(value.len().to_string().as_bytes());
+        self.out.push(b':');
+        self.out.extend_from_slice(value);
+    }
+
+    /// Bencode has no null, so `None` and unit are only accepted as a whole dictionary value,
+    /// which then produces no output and the entry is dropped.
+    fn write_nothing(&mut self) -> Result<(), BencodeError> {
+        if self.entry && self.out.is_empty() {
+            Ok(())
+        } else {
+            Err(unsupported("`None` or unit outside a dictionary value"))
+        }
+    }
+}
+
+fn unsupported(kind: &str) -> BencodeError {
+    BencodeError::Custom(format!("bencode cannot represent {}", kind))
+}
+
+impl<'a> ser::Serializer for &'a mut Serializer {
+    type Ok = ();
+    type Error = BencodeError;
+
+    type SerializeSeq = ListSerializer<'a>;
+    type SerializeTuple = ListSerializer<'a>;
+    type SerializeTupleStruct = ListSerializer<'a>;
+    type SerializeTupleVariant = ListSerializer<'a>;
+    type SerializeMap = DictSerializer<'a>;
+    type SerializeStruct = DictSerializer<'a>;
+    type SerializeStructVariant = DictSerializer<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), BencodeError> {
+        self.write_int(v as i64);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), BencodeError> {
+        self.write_int(v);
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), BencodeError> {
+        let value = i64::try_from(v).map_err(|_| unsupported("integers above i64::MAX"))?;
+        self.serialize_i64(value)
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<(), BencodeError> {
+        Err(unsupported("floating point numbers"))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<(), BencodeError> {
+        Err(unsupported("floating point numbers"))
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), BencodeError> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), BencodeError> {
+        self.write_bytes(v.as_bytes());
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), BencodeError> {
+        self.write_bytes(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), BencodeError> {
+        self.write_nothing()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), BencodeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), BencodeError> {
+        self.write_nothing()
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), BencodeError> {
+        self.write_nothing()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<(), BencodeError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), BencodeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), BencodeError> {
+        self.out.push(b'd');
+        self.write_bytes(variant.as_bytes());
+        value.serialize(&mut *self)?;
+        self.out.push(b'e');
+
+        Ok(())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<ListSerializer<'a>, BencodeError> {
+        self.out.push(b'l');
+
+        Ok(ListSerializer {
+            serializer: self,
+            variant: false,
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<ListSerializer<'a>, BencodeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer<'a>, BencodeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<ListSerializer<'a>, BencodeError> {
+        self.out.push(b'd');
+        self.write_bytes(variant.as_bytes());
+        self.out.push(b'l');
+
+        Ok(ListSerializer {
+            serializer: self,
+            variant: true,
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<DictSerializer<'a>, BencodeError> {
+        Ok(DictSerializer {
+            serializer: self,
+            entries: vec![],
+            key: None,
+            variant: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<DictSerializer<'a>, BencodeError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<DictSerializer<'a>, BencodeError> {
+        Ok(DictSerializer {
+            serializer: self,
+            entries: vec![],
+            key: None,
+            variant: Some(variant),
+        })
+    }
+}
+
+pub struct ListSerializer<'a> {
+    serializer: &'a mut Serializer,
+    variant: bool,
+}
+
+impl ListSerializer<'_> {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        value.serialize(&mut *self.serializer)
+    }
+
+    fn finish(self) -> Result<(), BencodeError> {
+        self.serializer.out.push(b'e');
+
+        if self.variant {
+            self.serializer.out.push(b'e');
+        }
+
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for ListSerializer<'_> {
+    type Ok = ();
+    type Error = BencodeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<(), BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for ListSerializer<'_> {
+    type Ok = ();
+    type Error = BencodeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<(), BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for ListSerializer<'_> {
+    type Ok = ();
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<(), BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for ListSerializer<'_> {
+    type Ok = ();
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<(), BencodeError> {
+        self.finish()
+    }
+}
+
+/// Buffers every entry so keys can be sorted by their raw bytes before anything is written, as
+/// serde hands us struct fields in declaration order.
+pub struct DictSerializer<'a> {
+    serializer: &'a mut Serializer,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    key: Option<Vec<u8>>,
+    variant: Option<&'static str>,
+}
+
+impl DictSerializer<'_> {
+    fn push_entry<T: Serialize + ?Sized>(
+        &mut self,
+        key: Vec<u8>,
+        value: &T,
+    ) -> Result<(), BencodeError> {
+        let mut serializer = Serializer {
+            out: vec![],
+            entry: true,
+        };
+        value.serialize(&mut serializer)?;
+
+        if !serializer.out.is_empty() {
+            self.entries.push((key, serializer.out));
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), BencodeError> {
+        self.entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        if self.entries.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+            return Err(BencodeError::Custom(String::from(
+                "duplicate dictionary key while serializing",
+            )));
+        }
+
+        let out = &mut self.serializer;
+
+        if let Some(variant) = self.variant {
+            out.out.push(b'd');
+            out.write_bytes(variant.as_bytes());
+        }
+
+        out.out.push(b'd');
+
+        for (key, value) in &self.entries {
+            out.write_bytes(key);
+            out.out.extend_from_slice(value);
+        }
+
+        out.out.push(b'e');
+
+        if self.variant.is_some() {
+            out.out.push(b'e');
+        }
+
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for DictSerializer<'_> {
+    type Ok = ();
+    type Error = BencodeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), BencodeError> {
+        self.key = Some(key.serialize(KeySerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        let key = self.key.take().ok_or(BencodeError::Custom(String::from(
+            "serialize_value called before serialize_key",
+        )))?;
+
+        self.push_entry(key, value)
+    }
+
+    fn end(self) -> Result<(), BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for DictSerializer<'_> {
+    type Ok = ();
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), BencodeError> {
+        self.push_entry(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<(), BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for DictSerializer<'_> {
+    type Ok = ();
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), BencodeError> {
+        self.push_entry(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<(), BencodeError> {
+        self.finish()
+    }
+}
+
+/// Dictionary keys have to be byte strings, so anything else is rejected up front.
+struct KeySerializer;
+
+impl ser::Serializer for KeySerializer {
+    type Ok = Vec<u8>;
+    type Error = BencodeError;
+
+    type SerializeSeq = Impossible<Vec<u8>, BencodeError>;
+    type SerializeTuple = Impossible<Vec<u8>, BencodeError>;
+    type SerializeTupleStruct = Impossible<Vec<u8>, BencodeError>;
+    type SerializeTupleVariant = Impossible<Vec<u8>, BencodeError>;
+    type SerializeMap = Impossible<Vec<u8>, BencodeError>;
+    type SerializeStruct = Impossible<Vec<u8>, BencodeError>;
+    type SerializeStructVariant = Impossible<Vec<u8>, BencodeError>;
+
+    fn serialize_bool(self, _v: bool) -> Result<Vec<u8>, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_i8(self, _v: i8) -> Result<Vec<u8>, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_i16(self, _v: i16) -> Result<Vec<u8>, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_i32(self, _v: i32) -> Result<Vec<u8>, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_i64(self, _v: i64) -> Result<Vec<u8>, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_u8(self, _v: u8) -> Result<Vec<u8>, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_u16(self, _v: u16) -> Result<Vec<u8>, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_u32(self, _v: u32) -> Result<Vec<u8>, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_u64(self, _v: u64) -> Result<Vec<u8>, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Vec<u8>, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Vec<u8>, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Vec<u8>, BencodeError> {
+        Ok(v.to_string().into_bytes())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Vec<u8>, BencodeError> {
+        Ok(v.as_bytes().to_vec())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Vec<u8>, BencodeError> {
+        Ok(v.to_vec())
+    }
+
+    fn serialize_none(self) -> Result<Vec<u8>, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>, BencodeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Vec<u8>, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Vec<u8>, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Vec<u8>, BencodeError> {
+        Ok(variant.as_bytes().to_vec())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Vec<u8>, BencodeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Vec<u8>, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, BencodeError> {
+        Err(unsupported("non-string dictionary keys"))
+    }
+}
@@ -0,0 +1,251 @@
+use super::{Bencode, BencodeError, BencodeState};
+
+#[derive(Debug)]
+pub enum DecodeStatus {
+    NeedMoreData,
+    /// A full value was decoded from the front of the buffer, consuming that many bytes.
+    Complete(BencodeState, usize),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Token {
+    Value,
+    Int,
+    Length(usize),
+    String(usize),
+}
+
+/// Push-style decoder for input that arrives in pieces (sockets, extension messages, DHT
+/// packets). Bytes are scanned only once across `feed` calls; a value is handed to the regular
+/// parser once its closing byte has been seen.
+#[derive(Debug)]
+pub struct StreamDecoder {
+    buffer: Vec<u8>,
+    position: usize,
+    depth: usize,
+    token: Token,
+    max_size: usize,
+    max_depth: usize,
+}
+
+impl Default for StreamDecoder {
+    fn default() -> Self {
+        Self {
+            buffer: vec![],
+            position: 0,
+            depth: 0,
+            token: Token::Value,
+            max_size: Self::DEFAULT_MAX_SIZE,
+            max_depth: Bencode::MAX_DEPTH,
+        }
+    }
+}
+
+impl StreamDecoder {
+    pub const DEFAULT_MAX_SIZE: usize = 4 * 1024 * 1024;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Limits are capped at `Bencode::MAX_DEPTH`, which the underlying parser enforces anyway.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth.min(Bencode::MAX_DEPTH);
+        self
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) {
+        self.buffer.extend_from_slice(chunk);
+    }
+
+    /// Bytes fed but not yet returned as part of a value.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Tries to take one complete value off the front of the buffer. After an error the
+    /// decoder should be dropped, as the stream can no longer be resynchronised.
+    pub fn decode(&mut self) -> Result<DecodeStatus, BencodeError> {
+        let Some(end) = self.scan()? else {
+            if self.buffer.len() > self.max_size {
+                return Err(BencodeError::TooLarge {
+                    offset: self.max_size,
+                });
+            }
+
+            return Ok(DecodeStatus::NeedMoreData);
+        };
+
+        if end > self.max_size {
+            return Err(BencodeError::TooLarge {
+                offset: self.max_size,
+            });
+        }
+
+        let value = Bencode::decode(&self.buffer[..end])?;
+
+        self.buffer.drain(..end);
+        self.position = 0;
+        self.depth = 0;
+        self.token = Token::Value;
+
+        Ok(DecodeStatus::Complete(value, end))
+    }
+
+    /// Advances over the buffered bytes and returns the end offset of the first complete value.
+    fn scan(&mut self) -> Result<Option<usize>, BencodeError> {
+        while self.position < self.buffer.len() {
+            let offset = self.position;
+            let byte = self.buffer[offset];
+
+            match self.token {
+                Token::Value => {
+                    match byte {
+                        b'd' | b'l' => {
+                            self.depth += 1;
+
+                            if self.depth > self.max_depth {
+                                return Err(BencodeError::NestingTooDeep { offset });
+                            }
+                        }
+                        b'i' => self.token = Token::Int,
+                        b'0'..=b'9' => self.token = Token::Length((byte - b'0') as usize),
+                        b'e' if self.depth > 0 => self.depth -= 1,
+                        byte => return Err(BencodeError::UnexpectedByte { byte, offset }),
+                    }
+
+                    self.position += 1;
+
+                    if byte == b'e' && self.depth == 0 {
+                        return Ok(Some(self.position));
+                    }
+                }
+                Token::Int => {
+                    self.position += 1;
+
+                    if byte == b'e' {
+                        self.token = Token::Value;
+
+                        if self.depth == 0 {
+                            return Ok(Some(self.position));
+                        }
+                    }
+                }
+                Token::Length(length) => {
+                    self.position += 1;
+
+                    self.token = match byte {
+                        b':' => Token::String(length),
+                        b'0'..=b'9' => Token::Length(
+                            length
+                                .checked_mul(10)
+                                .and_then(|it| it.checked_add((byte - b'0') as usize))
+                                .filter(|&it| it <= self.max_size)
+                                .ok_or(BencodeError::TooLarge { offset })?,
+                        ),
+                        _ => return Err(BencodeError::InvalidLength { offset }),
+                    };
+                }
+                Token::String(remaining) => {
+                    let available = remaining.min(self.buffer.len() - self.position);
+                    self.position += available;
+                    self.token = Token::String(remaining - available);
+                }
+            }
+
+            if let Token::String(0) = self.token {
+                self.token = Token::Value;
+
+                if self.depth == 0 {
+                    return Ok(Some(self.position));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_truncated_values() {
+        let input = b"d3:fooli1e4:spamee";
+        let mut decoder = StreamDecoder::new();
+
+        for byte in &input[..input.len() - 1] {
+            decoder.feed(&[*byte]);
+            assert!(matches!(decoder.decode(), Ok(DecodeStatus::NeedMoreData)));
+        }
+
+        decoder.feed(b"e");
+        assert!(matches!(
+            decoder.decode(),
+            Ok(DecodeStatus::Complete(BencodeState::Dictionary(..), length)) if length == input.len()
+        ));
+        assert!(decoder.buffered().is_empty());
+    }
+
+    #[test]
+    fn keeps_bytes_after_a_value() {
+        let mut decoder = StreamDecoder::new();
+        decoder.feed(b"i1e4:sp");
+
+        assert!(matches!(
+            decoder.decode(),
+            Ok(DecodeStatus::Complete(BencodeState::Int(1, _), 3))
+        ));
+        assert!(matches!(decoder.decode(), Ok(DecodeStatus::NeedMoreData)));
+        assert_eq!(decoder.buffered(), b"4:sp");
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let decode = |input: &[u8]| {
+            let mut decoder = StreamDecoder::new();
+            decoder.feed(input);
+            decoder.decode().unwrap_err()
+        };
+
+        assert_eq!(
+            decode(b"e"),
+            BencodeError::UnexpectedByte {
+                byte: b'e',
+                offset: 0
+            }
+        );
+        assert_eq!(decode(b"3x"), BencodeError::InvalidLength { offset: 1 });
+        assert_eq!(decode(b"i1xe"), BencodeError::InvalidInteger { offset: 0 });
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let mut decoder = StreamDecoder::new().with_max_size(4);
+        decoder.feed(b"10:abc");
+        assert_eq!(
+            decoder.decode().unwrap_err(),
+            BencodeError::TooLarge { offset: 1 }
+        );
+
+        let mut decoder = StreamDecoder::new().with_max_size(4);
+        decoder.feed(b"i12345");
+        assert_eq!(
+            decoder.decode().unwrap_err(),
+            BencodeError::TooLarge { offset: 4 }
+        );
+
+        let mut decoder = StreamDecoder::new().with_max_depth(2);
+        decoder.feed(b"lll");
+        assert_eq!(
+            decoder.decode().unwrap_err(),
+            BencodeError::NestingTooDeep { offset: 2 }
+        );
+    }
+}
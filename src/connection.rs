@@ -1,118 +1,425 @@
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use futures::{SinkExt, StreamExt};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
     sync::mpsc,
+    time::{Instant, interval, timeout},
 };
+use tokio_util::codec::Framed;
+
+use crate::connection_manager::{ConnectionCommand, ConnectionMessage};
+
+mod codec;
+mod handshake;
+
+pub use codec::{DEFAULT_MAX_MESSAGE_SIZE, PeerCodec, PeerMessage};
+pub use handshake::{Extensions, HANDSHAKE_LEN, Handshake, PROTOCOL};
+
+/// Pieces are requested in blocks of this size, the largest every client is expected to serve.
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+/// Requests kept outstanding per peer so the link never idles waiting for a round trip.
+pub const DEFAULT_PIPELINE_DEPTH: usize = 5;
+/// Time allowed to connect to a peer and exchange handshakes.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// A peer with requests outstanding that sends no block for this long is snubbing us.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// Peers drop connections silent for two minutes, so we speak up well before that.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+/// A peer that sent nothing at all, not even a keep-alive, for this long is gone.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+/// How often the timeouts above are checked.
+const TIMER_PERIOD: Duration = Duration::from_secs(5);
 
-use crate::connection_manager::ConnectionMessage;
-
-enum Messages {
-    Choke,
-    Unchoke,
-    Interested,
-    NotInterested,
-    Have,
-    Bitfield,
-    Request,
-    Piece,
-    Cancel,
-    KeepAlive,
+/// A piece assigned to this connection, filled block by block.
+#[derive(Debug)]
+struct PieceDownload {
+    index: u32,
+    data: Vec<u8>,
+    /// Blocks already in `data`, possibly from an earlier connection.
+    received: Vec<bool>,
+    requested: Vec<bool>,
 }
 
-impl Messages {
-    fn from_code(code: u8) -> Self {
-        match code {
-            0 => Self::Choke,
-            1 => Self::Unchoke,
-            2 => Self::Interested,
-            3 => Self::NotInterested,
-            4 => Self::Have,
-            5 => Self::Bitfield,
-            6 => Self::Request,
-            7 => Self::Piece,
-            8 => Self::Cancel,
-            _ => Self::KeepAlive,
-        }
+impl PieceDownload {
+    /// Offset and length of block `block`; only the last one may be shorter.
+    fn block_range(&self, block: usize) -> (u32, u32) {
+        let begin = block as u32 * BLOCK_SIZE;
+
+        (begin, BLOCK_SIZE.min(self.data.len() as u32 - begin))
     }
 }
 
+/// Number of blocks a piece of `length` bytes is requested in.
+pub fn block_count(length: u32) -> usize {
+    length.div_ceil(BLOCK_SIZE) as usize
+}
+
 #[derive(Debug)]
 pub struct Connection {
-    stream: TcpStream,
+    address: SocketAddr,
+    stream: Framed<TcpStream, PeerCodec>,
+    peer_id: [u8; 20],
+    /// Extensions the peer advertised in its handshake.
+    extensions: Extensions,
+    piece_count: usize,
+    pipeline_depth: usize,
+    /// Whether the peer is choking us; nothing is requested until it unchokes.
     choked: bool,
-    not_interested: bool,
-    available_pieces: Vec<usize>,
+    /// Whether we told the peer we want its pieces.
+    interested: bool,
 
-    tx: mpsc::Sender<ConnectionMessage>,
+    pieces: VecDeque<PieceDownload>,
+    /// Requests sent and not answered yet, as `(index, begin, length)`.
+    pending: Vec<(u32, u32, u32)>,
+    last_received: Instant,
+    last_sent: Instant,
+    /// When the last requested block arrived, or the first request of a batch went out.
+    last_block: Instant,
+
+    tx: mpsc::Sender<(SocketAddr, ConnectionMessage)>,
 }
 
 impl Connection {
     pub async fn initialize(
-        raw_info_hash: &[u8],
-        raw_peer_id: &[u8],
-        ip: &String,
-        port: &u64,
-        tx: mpsc::Sender<ConnectionMessage>,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        address: SocketAddr,
+        piece_count: usize,
+        tx: mpsc::Sender<(SocketAddr, ConnectionMessage)>,
     ) -> std::io::Result<Self> {
-        let mut stream = TcpStream::connect(format!("{}:{}", ip, port)).await?;
-
-        let handshake = Self::construct_handshake(raw_info_hash, raw_peer_id);
-        let mut data = vec![0; 68];
-
-        stream.write(&handshake).await?;
+        let local = Handshake {
+            extensions: Extensions::LOCAL,
+            info_hash,
+            peer_id,
+        };
 
-        stream.read(&mut data).await?;
+        let (stream, remote) = timeout(CONNECT_TIMEOUT, async {
+            let mut stream = TcpStream::connect(address).await?;
+            let remote = local.exchange(&mut stream).await?;
 
-        let success_message = if data[28..48] == handshake[28..48] {
-            "-> Success"
-        } else {
-            "-> Failure"
-        };
+            Ok::<_, Error>((stream, remote))
+        })
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "timed out connecting"))??;
 
-        println!("{}", success_message);
+        let fast = local.extensions.intersect(remote.extensions).fast;
+        let now = Instant::now();
 
         Ok(Connection {
+            address,
             tx,
-            stream,
+            stream: Framed::new(stream, PeerCodec::new().with_fast(fast)),
+            peer_id: remote.peer_id,
+            extensions: remote.extensions,
+            piece_count,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
             choked: true,
-            not_interested: true,
-            available_pieces: vec![],
+            interested: false,
+            pieces: VecDeque::new(),
+            pending: vec![],
+            last_received: now,
+            last_sent: now,
+            last_block: now,
         })
     }
 
-    pub async fn read_message(&mut self) -> std::io::Result<()> {
-        let mut length_data = vec![0u8; 4];
+    pub fn with_pipeline_depth(mut self, pipeline_depth: usize) -> Self {
+        self.pipeline_depth = pipeline_depth.max(1);
+        self
+    }
+
+    pub fn peer_id(&self) -> &[u8; 20] {
+        &self.peer_id
+    }
+
+    pub fn extensions(&self) -> Extensions {
+        self.extensions
+    }
+
+    pub async fn send_message(&mut self, message: PeerMessage) -> std::io::Result<()> {
+        self.last_sent = Instant::now();
+        self.stream.send(message).await
+    }
+
+    /// Handles incoming messages and piece assignments until the peer disconnects, sends
+    /// something malformed, goes silent or the manager goes away.
+    pub async fn run(
+        &mut self,
+        mut commands: mpsc::UnboundedReceiver<ConnectionCommand>,
+    ) -> std::io::Result<()> {
+        let mut timer = interval(TIMER_PERIOD);
+
         loop {
-            self.stream.read_exact(&mut length_data).await?;
+            tokio::select! {
+                message = self.stream.next() => match message {
+                    Some(message) => {
+                        self.last_received = Instant::now();
+                        self.handle_message(message?).await?;
+                    }
+                    None => return Ok(()),
+                },
+                command = commands.recv() => match command {
+                    Some(ConnectionCommand::Download { index, data, blocks }) => {
+                        self.pieces.push_back(PieceDownload {
+                            index: index as u32,
+                            data,
+                            requested: blocks.clone(),
+                            received: blocks,
+                        });
 
-            println!("{:?}", length_data);
+                        // Assigned before the manager learned we're choked; hand it back.
+                        if self.choked {
+                            self.release_pieces().await;
+                        }
+                    }
+                    Some(ConnectionCommand::Cancel { index }) => {
+                        self.cancel_piece(index as u32).await?;
+                    }
+                    None => return Ok(()),
+                },
+                _ = timer.tick() => self.check_timeouts().await?,
+            }
 
-            let bytes: [u8; 4] = length_data.clone().try_into().unwrap();
+            self.request_blocks().await?;
+        }
+    }
 
-            let mut message: Vec<u8> = vec![0; u32::from_be_bytes(bytes) as usize];
+    async fn handle_message(&mut self, message: PeerMessage) -> std::io::Result<()> {
+        match message {
+            PeerMessage::KeepAlive => {}
+            PeerMessage::Choke => {
+                self.choked = true;
 
-            self.stream.read_exact(&mut message).await?;
+                // Pending requests are dropped by a choking peer; give the pieces back so
+                // unchoked peers can fetch them.
+                self.report(ConnectionMessage::Choked).await;
+                self.release_pieces().await;
+            }
+            PeerMessage::Unchoke => {
+                self.choked = false;
+                self.report(ConnectionMessage::Unchoked).await;
+            }
+            // Nothing is uploaded yet, so the peer's interest doesn't change anything.
+            PeerMessage::Interested | PeerMessage::NotInterested => {}
+            PeerMessage::Have(index) => {
+                self.announce_pieces(vec![index as usize]).await?;
+            }
+            PeerMessage::Bitfield(bitfield) => {
+                let pieces = (0..self.piece_count.min(bitfield.len() * 8))
+                    .filter(|index| bitfield[index / 8] & (0x80 >> (index % 8)) != 0)
+                    .collect();
 
-            println!("{:?}", message);
+                self.announce_pieces(pieces).await?;
+            }
+            PeerMessage::HaveAll => {
+                self.announce_pieces((0..self.piece_count).collect())
+                    .await?;
+            }
+            PeerMessage::HaveNone => {}
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => self.receive_block(index, begin, block).await,
+            PeerMessage::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                if self.pending.contains(&(index, begin, length))
+                    && let Some(slot) = self.pieces.iter().position(|piece| piece.index == index)
+                {
+                    // The peer won't serve this piece; let another one have it.
+                    let piece = self.pieces.remove(slot).unwrap();
 
-            if !message.len() == 0 {
-                return Ok(());
+                    self.pending.retain(|&(pending, ..)| pending != index);
+                    self.report(ConnectionMessage::PieceRejected(index as usize))
+                        .await;
+                    self.release_piece(piece).await;
+                }
             }
+            // Nothing is uploaded yet, so requests for our pieces go unanswered.
+            PeerMessage::Request { .. } | PeerMessage::Cancel { .. } => {}
+            // Hints only; DHT isn't implemented and pieces are only requested while unchoked.
+            PeerMessage::Port(_) | PeerMessage::Suggest(_) | PeerMessage::AllowedFast(_) => {}
+            // No extension handshake is sent, so none of these are expected.
+            PeerMessage::Extended { .. } => {}
         }
+
+        Ok(())
     }
 
-    fn construct_handshake(raw_info_hash: &[u8], raw_peer_id: &[u8]) -> Vec<u8> {
-        let mut handshake = Vec::with_capacity(68);
+    /// Copies a requested block into its piece and reports the piece once every block is in.
+    async fn receive_block(&mut self, index: u32, begin: u32, block: Vec<u8>) {
+        let Some(position) = self
+            .pending
+            .iter()
+            .position(|&it| it == (index, begin, block.len() as u32))
+        else {
+            // Unrequested, cancelled or wrongly sized; a well-behaved peer never sends these.
+            return;
+        };
+
+        self.pending.remove(position);
+        self.last_block = Instant::now();
+
+        let Some(slot) = self.pieces.iter().position(|piece| piece.index == index) else {
+            return;
+        };
 
-        handshake.push(19);
-        handshake.extend_from_slice(b"BitTorrent protocol");
+        let piece = &mut self.pieces[slot];
+        piece.data[begin as usize..begin as usize + block.len()].copy_from_slice(&block);
+        piece.received[(begin / BLOCK_SIZE) as usize] = true;
 
-        handshake.extend_from_slice(&[0u8; 8]);
+        if piece.received.iter().all(|received| *received) {
+            let piece = self.pieces.remove(slot).unwrap();
+
+            self.report(ConnectionMessage::PieceRecieved(
+                piece.index as usize,
+                piece.data,
+            ))
+            .await;
+        }
+    }
+
+    /// Tops the pipeline up with requests for the next blocks of the assigned pieces.
+    async fn request_blocks(&mut self) -> std::io::Result<()> {
+        if self.choked {
+            return Ok(());
+        }
 
-        handshake.extend_from_slice(raw_info_hash);
+        let mut requests = vec![];
 
-        handshake.extend_from_slice(raw_peer_id);
+        for piece in self.pieces.iter_mut() {
+            for block in 0..piece.requested.len() {
+                if self.pending.len() + requests.len() >= self.pipeline_depth {
+                    break;
+                }
+
+                if !piece.requested[block] {
+                    let (begin, length) = piece.block_range(block);
+
+                    requests.push((piece.index, begin, length));
+                    piece.requested[block] = true;
+                }
+            }
+        }
+
+        if requests.is_empty() {
+            return Ok(());
+        }
+
+        // A fresh batch; the peer gets the full timeout to answer it.
+        if self.pending.is_empty() {
+            self.last_block = Instant::now();
+        }
+
+        self.last_sent = Instant::now();
+
+        for (index, begin, length) in requests {
+            self.pending.push((index, begin, length));
+
+            self.stream
+                .feed(PeerMessage::Request {
+                    index,
+                    begin,
+                    length,
+                })
+                .await?;
+        }
+
+        self.stream.flush().await
+    }
+
+    /// Drops a piece another peer finished first, cancelling the requests still out for it.
+    async fn cancel_piece(&mut self, index: u32) -> std::io::Result<()> {
+        self.pieces.retain(|piece| piece.index != index);
+
+        let cancelled = self
+            .pending
+            .extract_if(.., |&mut (pending, ..)| pending == index)
+            .collect::<Vec<_>>();
+
+        if cancelled.is_empty() {
+            return Ok(());
+        }
+
+        self.last_sent = Instant::now();
+
+        for (index, begin, length) in cancelled {
+            self.stream
+                .feed(PeerMessage::Cancel {
+                    index,
+                    begin,
+                    length,
+                })
+                .await?;
+        }
+
+        self.stream.flush().await
+    }
+
+    /// Gives up on peers that went silent or stopped serving our requests, and sends a
+    /// keep-alive when we have been quiet for a while.
+    async fn check_timeouts(&mut self) -> std::io::Result<()> {
+        if self.last_received.elapsed() > IDLE_TIMEOUT {
+            return Err(Error::new(ErrorKind::TimedOut, "peer went silent"));
+        }
+
+        if !self.pending.is_empty() && self.last_block.elapsed() > REQUEST_TIMEOUT {
+            // Snubbed; whatever arrived so far goes to a peer that does serve it.
+            self.release_pieces().await;
+
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                "peer stopped serving requests",
+            ));
+        }
+
+        if self.last_sent.elapsed() > KEEP_ALIVE_INTERVAL {
+            self.send_message(PeerMessage::KeepAlive).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Hands every assigned piece back to the manager, with whatever blocks arrived so far.
+    pub async fn release_pieces(&mut self) {
+        self.pending.clear();
+
+        while let Some(piece) = self.pieces.pop_front() {
+            self.release_piece(piece).await;
+        }
+    }
+
+    async fn release_piece(&self, piece: PieceDownload) {
+        self.report(ConnectionMessage::PieceReleased(
+            piece.index as usize,
+            piece.data,
+            piece.received,
+        ))
+        .await;
+    }
+
+    async fn announce_pieces(&mut self, pieces: Vec<usize>) -> std::io::Result<()> {
+        if !self.interested && !pieces.is_empty() {
+            self.interested = true;
+            self.send_message(PeerMessage::Interested).await?;
+        }
+
+        self.report(ConnectionMessage::PiecesAvailable(pieces))
+            .await;
+
+        Ok(())
+    }
 
-        handshake
+    async fn report(&self, message: ConnectionMessage) {
+        let _ = self.tx.send((self.address, message)).await;
     }
 }
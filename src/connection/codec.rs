@@ -0,0 +1,461 @@
+use std::io::{Error, ErrorKind};
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Largest message accepted by default: room for a 16 KiB block plus headers, and for the
+/// bitfield of torrents with up to two million pieces.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 256 * 1024;
+
+/// A message of the peer wire protocol (BEP 3), plus the Fast extension (BEP 6) and the
+/// extension protocol message (BEP 10).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// DHT port of the peer (BEP 5).
+    Port(u16),
+    /// Fast extension (BEP 6) messages, only valid once both peers advertised it.
+    Suggest(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    AllowedFast(u32),
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+impl PeerMessage {
+    pub const CHOKE: u8 = 0;
+    pub const UNCHOKE: u8 = 1;
+    pub const INTERESTED: u8 = 2;
+    pub const NOT_INTERESTED: u8 = 3;
+    pub const HAVE: u8 = 4;
+    pub const BITFIELD: u8 = 5;
+    pub const REQUEST: u8 = 6;
+    pub const PIECE: u8 = 7;
+    pub const CANCEL: u8 = 8;
+    pub const PORT: u8 = 9;
+    pub const SUGGEST: u8 = 13;
+    pub const HAVE_ALL: u8 = 14;
+    pub const HAVE_NONE: u8 = 15;
+    pub const REJECT_REQUEST: u8 = 16;
+    pub const ALLOWED_FAST: u8 = 17;
+    pub const EXTENDED: u8 = 20;
+
+    /// Message id on the wire; `None` for keep-alives, which have none.
+    pub fn id(&self) -> Option<u8> {
+        Some(match self {
+            Self::KeepAlive => return None,
+            Self::Choke => Self::CHOKE,
+            Self::Unchoke => Self::UNCHOKE,
+            Self::Interested => Self::INTERESTED,
+            Self::NotInterested => Self::NOT_INTERESTED,
+            Self::Have(_) => Self::HAVE,
+            Self::Bitfield(_) => Self::BITFIELD,
+            Self::Request { .. } => Self::REQUEST,
+            Self::Piece { .. } => Self::PIECE,
+            Self::Cancel { .. } => Self::CANCEL,
+            Self::Port(_) => Self::PORT,
+            Self::Suggest(_) => Self::SUGGEST,
+            Self::HaveAll => Self::HAVE_ALL,
+            Self::HaveNone => Self::HAVE_NONE,
+            Self::RejectRequest { .. } => Self::REJECT_REQUEST,
+            Self::AllowedFast(_) => Self::ALLOWED_FAST,
+            Self::Extended { .. } => Self::EXTENDED,
+        })
+    }
+
+    /// Whether the message belongs to the Fast extension.
+    pub fn is_fast(&self) -> bool {
+        matches!(
+            self,
+            Self::Suggest(_)
+                | Self::HaveAll
+                | Self::HaveNone
+                | Self::RejectRequest { .. }
+                | Self::AllowedFast(_)
+        )
+    }
+
+    /// Parses a message from its id and payload, checking the payload has the length the id
+    /// calls for.
+    pub fn from_parts(id: u8, mut payload: &[u8]) -> Result<Self, Error> {
+        let expect = |length: usize| {
+            if payload.len() == length {
+                Ok(())
+            } else {
+                Err(invalid(format!(
+                    "message {} has a {} byte payload, expected {}",
+                    id,
+                    payload.len(),
+                    length
+                )))
+            }
+        };
+
+        Ok(match id {
+            Self::CHOKE => expect(0).map(|_| Self::Choke)?,
+            Self::UNCHOKE => expect(0).map(|_| Self::Unchoke)?,
+            Self::INTERESTED => expect(0).map(|_| Self::Interested)?,
+            Self::NOT_INTERESTED => expect(0).map(|_| Self::NotInterested)?,
+            Self::HAVE | Self::SUGGEST | Self::ALLOWED_FAST => {
+                expect(4)?;
+
+                let index = payload.get_u32();
+
+                match id {
+                    Self::HAVE => Self::Have(index),
+                    Self::SUGGEST => Self::Suggest(index),
+                    _ => Self::AllowedFast(index),
+                }
+            }
+            Self::BITFIELD => Self::Bitfield(payload.to_vec()),
+            Self::REQUEST | Self::CANCEL | Self::REJECT_REQUEST => {
+                expect(12)?;
+
+                let (index, begin, length) =
+                    (payload.get_u32(), payload.get_u32(), payload.get_u32());
+
+                match id {
+                    Self::REQUEST => Self::Request {
+                        index,
+                        begin,
+                        length,
+                    },
+                    Self::CANCEL => Self::Cancel {
+                        index,
+                        begin,
+                        length,
+                    },
+                    _ => Self::RejectRequest {
+                        index,
+                        begin,
+                        length,
+                    },
+                }
+            }
+            Self::PIECE => {
+                if payload.len() < 8 {
+                    return Err(invalid(String::from("piece message is truncated")));
+                }
+
+                Self::Piece {
+                    index: payload.get_u32(),
+                    begin: payload.get_u32(),
+                    block: payload.to_vec(),
+                }
+            }
+            Self::PORT => {
+                expect(2)?;
+                Self::Port(payload.get_u16())
+            }
+            Self::HAVE_ALL => expect(0).map(|_| Self::HaveAll)?,
+            Self::HAVE_NONE => expect(0).map(|_| Self::HaveNone)?,
+            Self::EXTENDED => match payload.split_first() {
+                Some((&id, payload)) => Self::Extended {
+                    id,
+                    payload: payload.to_vec(),
+                },
+                None => return Err(invalid(String::from("extended message has no id"))),
+            },
+            _ => return Err(invalid(format!("unknown message id {}", id))),
+        })
+    }
+
+    fn payload_len(&self) -> usize {
+        match self {
+            Self::KeepAlive
+            | Self::Choke
+            | Self::Unchoke
+            | Self::Interested
+            | Self::NotInterested
+            | Self::HaveAll
+            | Self::HaveNone => 0,
+            Self::Have(_) | Self::Suggest(_) | Self::AllowedFast(_) => 4,
+            Self::Bitfield(bitfield) => bitfield.len(),
+            Self::Request { .. } | Self::Cancel { .. } | Self::RejectRequest { .. } => 12,
+            Self::Piece { block, .. } => 8 + block.len(),
+            Self::Port(_) => 2,
+            Self::Extended { payload, .. } => 1 + payload.len(),
+        }
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Length-prefixed framing of `PeerMessage`s, for use with `tokio_util::codec::Framed` once the
+/// handshake is done.
+#[derive(Debug, Clone)]
+pub struct PeerCodec {
+    max_size: usize,
+    fast: bool,
+}
+
+impl Default for PeerCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PeerCodec {
+    pub fn new() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_MESSAGE_SIZE,
+            fast: false,
+        }
+    }
+
+    /// Messages longer than this (id included) are rejected, in both directions.
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Accepts the Fast extension messages; without it their ids are unknown, in both directions.
+    pub fn with_fast(mut self, fast: bool) -> Self {
+        self.fast = fast;
+        self
+    }
+
+    fn check_fast(&self, message: &PeerMessage) -> Result<(), Error> {
+        if message.is_fast() && !self.fast {
+            return Err(invalid(format!(
+                "message id {} requires the Fast extension",
+                message.id().unwrap_or_default()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl Decoder for PeerCodec {
+    type Item = PeerMessage;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some(prefix) = src.get(..4) else {
+            return Ok(None);
+        };
+
+        let length = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+
+        if length > self.max_size {
+            return Err(invalid(format!(
+                "message of {} bytes exceeds the {} byte limit",
+                length, self.max_size
+            )));
+        }
+
+        if src.len() < 4 + length {
+            src.reserve(4 + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(4);
+        let message = src.split_to(length);
+
+        let message = match message.split_first() {
+            Some((&id, payload)) => PeerMessage::from_parts(id, payload)?,
+            None => PeerMessage::KeepAlive,
+        };
+
+        self.check_fast(&message)?;
+
+        Ok(Some(message))
+    }
+}
+
+impl Encoder<PeerMessage> for PeerCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: PeerMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.check_fast(&item)?;
+
+        let length = item.id().map_or(0, |_| 1 + item.payload_len());
+
+        if length > self.max_size {
+            return Err(invalid(format!(
+                "message of {} bytes exceeds the {} byte limit",
+                length, self.max_size
+            )));
+        }
+
+        dst.reserve(4 + length);
+        dst.put_u32(length as u32);
+
+        if let Some(id) = item.id() {
+            dst.put_u8(id);
+        }
+
+        match item {
+            PeerMessage::KeepAlive
+            | PeerMessage::Choke
+            | PeerMessage::Unchoke
+            | PeerMessage::Interested
+            | PeerMessage::NotInterested
+            | PeerMessage::HaveAll
+            | PeerMessage::HaveNone => {}
+            PeerMessage::Have(index)
+            | PeerMessage::Suggest(index)
+            | PeerMessage::AllowedFast(index) => dst.put_u32(index),
+            PeerMessage::Bitfield(bitfield) => dst.put_slice(&bitfield),
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            }
+            | PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            }
+            | PeerMessage::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                dst.put_u32(index);
+                dst.put_u32(begin);
+                dst.put_u32(length);
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                dst.put_u32(index);
+                dst.put_u32(begin);
+                dst.put_slice(&block);
+            }
+            PeerMessage::Port(port) => dst.put_u16(port),
+            PeerMessage::Extended { id, payload } => {
+                dst.put_u8(id);
+                dst.put_slice(&payload);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(codec: &mut PeerCodec, input: &[u8]) -> Result<Option<PeerMessage>, Error> {
+        codec.decode(&mut BytesMut::from(input))
+    }
+
+    #[test]
+    fn waits_for_truncated_messages() {
+        let message = PeerMessage::Piece {
+            index: 1,
+            begin: 0,
+            block: vec![7; 32],
+        };
+
+        let mut encoded = BytesMut::new();
+        PeerCodec::new()
+            .encode(message.clone(), &mut encoded)
+            .unwrap();
+
+        let (last, head) = encoded.split_last().unwrap();
+        let mut codec = PeerCodec::new();
+        let mut buffer = BytesMut::new();
+
+        for byte in head {
+            buffer.put_u8(*byte);
+            assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+        }
+
+        buffer.put_u8(*last);
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(message));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn rejects_oversized_messages() {
+        let mut codec = PeerCodec::new().with_max_size(16);
+
+        // Rejected from the length prefix alone, before the body arrives.
+        let error = decode(&mut codec, &17u32.to_be_bytes()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        let error = decode(&mut PeerCodec::new(), &u32::MAX.to_be_bytes()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_malformed_payloads() {
+        for input in [
+            // Choke with a payload.
+            &[0, 0, 0, 2, PeerMessage::CHOKE, 0][..],
+            // Have with a 3 byte index.
+            &[0, 0, 0, 4, PeerMessage::HAVE, 0, 0, 1],
+            // Request missing its length.
+            &[0, 0, 0, 9, PeerMessage::REQUEST, 0, 0, 0, 1, 0, 0, 0, 0],
+            // Piece without a full header.
+            &[0, 0, 0, 5, PeerMessage::PIECE, 0, 0, 0, 1],
+            // Port with one byte.
+            &[0, 0, 0, 2, PeerMessage::PORT, 1],
+            // Extended without an id.
+            &[0, 0, 0, 1, PeerMessage::EXTENDED],
+            // Unknown id.
+            &[0, 0, 0, 1, 99],
+        ] {
+            let error = decode(&mut PeerCodec::new().with_fast(true), input).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_fast_messages_unless_negotiated() {
+        let input = [0, 0, 0, 1, PeerMessage::HAVE_ALL];
+
+        let error = decode(&mut PeerCodec::new(), &input).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        assert_eq!(
+            decode(&mut PeerCodec::new().with_fast(true), &input).unwrap(),
+            Some(PeerMessage::HaveAll)
+        );
+    }
+
+    #[test]
+    fn decodes_keep_alives() {
+        assert_eq!(
+            decode(&mut PeerCodec::new(), &[0, 0, 0, 0]).unwrap(),
+            Some(PeerMessage::KeepAlive)
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use std::io::{Error, ErrorKind};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+pub const HANDSHAKE_LEN: usize = 68;
+
+/// Protocol extensions announced through the reserved bytes of the handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Extensions {
+    /// Extension protocol (BEP 10).
+    pub extended: bool,
+    /// Fast extension (BEP 6).
+    pub fast: bool,
+    /// DHT (BEP 5).
+    pub dht: bool,
+}
+
+impl Extensions {
+    /// What this client supports; DHT isn't implemented so it isn't advertised.
+    pub const LOCAL: Self = Self {
+        extended: true,
+        fast: true,
+        dht: false,
+    };
+
+    pub fn from_reserved(reserved: &[u8; 8]) -> Self {
+        Self {
+            extended: reserved[5] & 0x10 != 0,
+            fast: reserved[7] & 0x04 != 0,
+            dht: reserved[7] & 0x01 != 0,
+        }
+    }
+
+    pub fn to_reserved(self) -> [u8; 8] {
+        let mut reserved = [0u8; 8];
+
+        if self.extended {
+            reserved[5] |= 0x10;
+        }
+
+        if self.fast {
+            reserved[7] |= 0x04;
+        }
+
+        if self.dht {
+            reserved[7] |= 0x01;
+        }
+
+        reserved
+    }
+
+    /// The extensions both sides advertised, which are the only ones either may use.
+    pub fn intersect(self, other: Self) -> Self {
+        Self {
+            extended: self.extended && other.extended,
+            fast: self.fast && other.fast,
+            dht: self.dht && other.dht,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub extensions: Extensions,
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut handshake = Vec::with_capacity(HANDSHAKE_LEN);
+
+        handshake.push(PROTOCOL.len() as u8);
+        handshake.extend_from_slice(PROTOCOL);
+        handshake.extend_from_slice(&self.extensions.to_reserved());
+        handshake.extend_from_slice(&self.info_hash);
+        handshake.extend_from_slice(&self.peer_id);
+
+        handshake
+    }
+
+    /// Sends our handshake and reads the peer's, failing if it speaks another protocol or
+    /// serves a different torrent.
+    pub async fn exchange(&self, stream: &mut TcpStream) -> std::io::Result<Self> {
+        stream.write_all(&self.to_bytes()).await?;
+
+        let mut response = [0u8; HANDSHAKE_LEN];
+        stream.read_exact(&mut response).await?;
+
+        if response[0] as usize != PROTOCOL.len() || &response[1..20] != PROTOCOL {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "peer does not speak the BitTorrent protocol",
+            ));
+        }
+
+        let remote = Self {
+            extensions: Extensions::from_reserved(response[20..28].try_into().unwrap()),
+            info_hash: response[28..48].try_into().unwrap(),
+            peer_id: response[48..68].try_into().unwrap(),
+        };
+
+        if remote.info_hash != self.info_hash {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "peer answered with a different info hash",
+            ));
+        }
+
+        Ok(remote)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const INFO_HASH: [u8; 20] = [1; 20];
+
+    fn local() -> Handshake {
+        Handshake {
+            extensions: Extensions::LOCAL,
+            info_hash: INFO_HASH,
+            peer_id: [2; 20],
+        }
+    }
+
+    /// Runs our side of the handshake against a peer that answers with `response`.
+    async fn exchange_with(response: Vec<u8>) -> std::io::Result<Handshake> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; HANDSHAKE_LEN];
+
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(&response).await.unwrap();
+        });
+
+        local()
+            .exchange(&mut TcpStream::connect(address).await.unwrap())
+            .await
+    }
+
+    #[test]
+    fn round_trips_reserved_bits() {
+        let all = Extensions {
+            extended: true,
+            fast: true,
+            dht: true,
+        };
+
+        assert_eq!(all.to_reserved(), [0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+        assert_eq!(Extensions::from_reserved(&all.to_reserved()), all);
+        assert_eq!(Extensions::from_reserved(&[0xff; 8]), all);
+        assert_eq!(Extensions::from_reserved(&[0; 8]), Extensions::default());
+        assert_eq!(all.intersect(Extensions::LOCAL), Extensions::LOCAL);
+    }
+
+    #[tokio::test]
+    async fn accepts_a_matching_handshake() {
+        let remote = Handshake {
+            extensions: Extensions {
+                dht: true,
+                ..Default::default()
+            },
+            info_hash: INFO_HASH,
+            peer_id: [3; 20],
+        };
+
+        assert_eq!(exchange_with(remote.to_bytes()).await.unwrap(), remote);
+    }
+
+    #[tokio::test]
+    async fn rejects_another_protocol() {
+        let mut response = local().to_bytes();
+        response[1] = b'b';
+
+        let error = exchange_with(response).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        let mut response = local().to_bytes();
+        response[0] = 18;
+
+        let error = exchange_with(response).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejects_another_info_hash() {
+        let response = Handshake {
+            info_hash: [9; 20],
+            ..local()
+        }
+        .to_bytes();
+
+        let error = exchange_with(response).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_truncated_handshake() {
+        let mut response = local().to_bytes();
+        response.truncate(HANDSHAKE_LEN - 1);
+
+        let error = exchange_with(response).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -1,6 +1,10 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
-use futures::future::join_all;
+use sha1::{Digest, Sha1};
 use tokio::sync::mpsc;
 
 /*
@@ -9,83 +13,473 @@ use tokio::sync::mpsc;
  * Connection should worry about peer to which is connected to and thats it, the root context will
  * access the available pieces and thats it.
  */
-use crate::{connection::Connection, tracker::Peer};
+use crate::{
+    announcer::{AnnouncerHandle, TransferStats},
+    connection::{Connection, DEFAULT_PIPELINE_DEPTH, block_count},
+    piece_picker::PiecePicker,
+    storage::Storage,
+    tracker::Peer,
+};
 
+/// Pieces handed to a peer at once; the second keeps its pipeline full while the first finishes.
+const PIECES_PER_PEER: usize = 2;
+/// Pieces failing their hash check before the peer that sent them is banned.
+pub const DEFAULT_BAN_THRESHOLD: u32 = 3;
+
+/// Sent by a connection to the manager, tagged with the peer's address.
+#[derive(Debug)]
 pub enum ConnectionMessage {
     PieceRecieved(usize, Vec<u8>),
     PiecesAvailable(Vec<usize>),
+    /// An unfinished piece handed back, with the blocks that did arrive marked.
+    PieceReleased(usize, Vec<u8>, Vec<bool>),
+    /// The peer rejected a request (BEP 6); the piece is released right after.
+    PieceRejected(usize),
+    /// The peer choked us; every assigned piece is released right after.
+    Choked,
+    Unchoked,
+    Disconnected,
+}
+
+/// Sent by the manager to a connection.
+#[derive(Debug)]
+pub enum ConnectionCommand {
+    /// Fetches the blocks of a piece not yet marked in `blocks`, keeping those already in `data`.
+    Download {
+        index: usize,
+        data: Vec<u8>,
+        blocks: Vec<bool>,
+    },
+    /// Drops a piece another peer finished first, cancelling its outstanding requests.
+    Cancel { index: usize },
+}
+
+/// What the manager knows about a connected peer.
+#[derive(Debug)]
+struct PeerState {
+    commands: mpsc::UnboundedSender<ConnectionCommand>,
+    pieces: HashSet<usize>,
+    assigned: HashSet<usize>,
+    choked: bool,
 }
 
 #[derive(Debug)]
 pub struct ConnectionManager {
-    piece_hashes: Vec<String>,
-    tracker_interval: u64,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    piece_hashes: Vec<[u8; 20]>,
+    piece_length: u64,
+    total_length: u64,
+    pipeline_depth: usize,
+    ban_threshold: u32,
+    known_peers: HashSet<SocketAddr>,
+    /// Pieces each address sent that failed verification.
+    strikes: HashMap<IpAddr, u32>,
+    banned: HashSet<IpAddr>,
 
-    rx: mpsc::Receiver<ConnectionMessage>,
-    tx: mpsc::Sender<ConnectionMessage>,
+    peers: HashMap<SocketAddr, PeerState>,
+    picker: PiecePicker,
+    /// Blocks of unfinished pieces, kept until another peer resumes them.
+    partial: HashMap<usize, (Vec<u8>, Vec<bool>)>,
+    /// Peers that sent blocks of each unfinished piece; all are blamed if it fails to verify.
+    contributors: HashMap<usize, HashSet<SocketAddr>>,
+
+    /// Where verified pieces are written; without one they are only checked.
+    storage: Option<Storage>,
+    stats: Arc<TransferStats>,
+    announcer: AnnouncerHandle,
+
+    rx: mpsc::Receiver<(SocketAddr, ConnectionMessage)>,
+    tx: mpsc::Sender<(SocketAddr, ConnectionMessage)>,
 }
 
 impl ConnectionManager {
-    pub async fn new(
-        peers: &[Peer],
-        raw_info_hash: Vec<u8>,
-        peer_id: String,
-        piece_hashes: Vec<String>,
-        tracker_interval: u64,
+    pub fn new(
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        piece_hashes: Vec<[u8; 20]>,
+        piece_length: u64,
+        total_length: u64,
+        stats: Arc<TransferStats>,
+        announcer: AnnouncerHandle,
     ) -> Self {
-        let (tx, rx) = mpsc::channel::<ConnectionMessage>(100);
-
-        let connections = join_all(peers.iter().map(async |peer| {
-            let result = Connection::initialize(
-                &raw_info_hash,
-                peer_id.as_bytes(),
-                &peer.ip,
-                &peer.port,
-                tx.clone(),
-            )
-            .await;
-
-            if let Ok(conn) = result {
-                return Some(conn);
+        let (tx, rx) = mpsc::channel(100);
+
+        ConnectionManager {
+            info_hash,
+            peer_id,
+            picker: PiecePicker::new(piece_hashes.len()),
+            piece_hashes,
+            piece_length,
+            total_length,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            ban_threshold: DEFAULT_BAN_THRESHOLD,
+            known_peers: HashSet::new(),
+            strikes: HashMap::new(),
+            banned: HashSet::new(),
+            peers: HashMap::new(),
+            partial: HashMap::new(),
+            contributors: HashMap::new(),
+            storage: None,
+            stats,
+            announcer,
+            rx,
+            tx,
+        }
+    }
+
+    /// Block requests kept outstanding per peer.
+    pub fn with_pipeline_depth(mut self, pipeline_depth: usize) -> Self {
+        self.pipeline_depth = pipeline_depth.max(1);
+        self
+    }
+
+    /// Bad pieces a peer may send before it is disconnected and never contacted again.
+    pub fn with_ban_threshold(mut self, ban_threshold: u32) -> Self {
+        self.ban_threshold = ban_threshold.max(1);
+        self
+    }
+
+    /// Pieces fetched in random order before switching to rarest-first.
+    pub fn with_random_first(mut self, random_first: usize) -> Self {
+        self.picker = self.picker.with_random_first(random_first);
+        self
+    }
+
+    /// Writes every verified piece to `storage`.
+    pub fn with_storage(mut self, storage: Storage) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Length of piece `index`; only the last one may be shorter than `piece_length`.
+    fn piece_size(&self, index: usize) -> u32 {
+        let start = index as u64 * self.piece_length;
+
+        self.piece_length
+            .min(self.total_length.saturating_sub(start)) as u32
+    }
+
+    /// Opens a connection to every peer not already connected or banned.
+    fn connect(&mut self, peers: &[Peer]) {
+        for peer in peers {
+            if self.banned.contains(&peer.address.ip()) || !self.known_peers.insert(peer.address) {
+                continue;
             }
 
-            None
-        }))
-        .await
-        .into_iter()
-        .filter_map(|conn| conn)
-        .collect::<Vec<Connection>>();
+            let address = peer.address;
+            let info_hash = self.info_hash;
+            let peer_id = self.peer_id;
+            let piece_count = self.piece_hashes.len();
+            let pipeline_depth = self.pipeline_depth;
+            let tx = self.tx.clone();
 
-        println!("{:#?}", connections);
-        println!("{:#?}", tracker_interval);
+            let (commands, commands_rx) = mpsc::unbounded_channel();
 
-        for mut conn in connections {
-            tokio::spawn(async move { conn.read_message().await });
+            self.peers.insert(
+                address,
+                PeerState {
+                    commands,
+                    pieces: HashSet::new(),
+                    assigned: HashSet::new(),
+                    choked: true,
+                },
+            );
+
+            tokio::spawn(async move {
+                let result = match Connection::initialize(
+                    info_hash,
+                    peer_id,
+                    address,
+                    piece_count,
+                    tx.clone(),
+                )
+                .await
+                {
+                    Ok(conn) => {
+                        let mut conn = conn.with_pipeline_depth(pipeline_depth);
+                        let result = conn.run(commands_rx).await;
+
+                        conn.release_pieces().await;
+                        result
+                    }
+                    Err(err) => Err(err),
+                };
+
+                if let Err(err) = result {
+                    eprintln!("{} -> {}", address, err);
+                }
+
+                let _ = tx.send((address, ConnectionMessage::Disconnected)).await;
+            });
         }
+    }
 
-        ConnectionManager {
-            rx,
-            tx,
-            piece_hashes,
-            tracker_interval,
+    /// Peers currently fetching piece `index`; more than one only in endgame.
+    fn holders(&self, index: usize) -> usize {
+        self.peers
+            .values()
+            .filter(|peer| peer.assigned.contains(&index))
+            .count()
+    }
+
+    /// In endgame, the piece in progress elsewhere that the fewest peers are fetching, so the
+    /// last pieces aren't left waiting on a single slow peer.
+    fn pick_duplicate(&self, peer: &PeerState) -> Option<usize> {
+        peer.pieces
+            .iter()
+            .copied()
+            .filter(|&index| self.picker.is_in_progress(index) && !peer.assigned.contains(&index))
+            .min_by_key(|&index| self.holders(index))
+    }
+
+    /// Returns a piece to the pool once nobody is fetching it, keeping any blocks that arrived.
+    fn release(&mut self, index: usize, data: Vec<u8>, blocks: Vec<bool>) {
+        if self.holders(index) > 0 {
+            return;
         }
+
+        let partial = blocks.iter().any(|block| *block);
+
+        if partial {
+            self.partial.insert(index, (data, blocks));
+        }
+
+        self.picker.release(index, partial);
     }
 
-    pub async fn download(&mut self) {
-        while let Some(msg) = self.rx.recv().await {
-            match msg {
-                ConnectionMessage::PiecesAvailable(pieces) => {
-                    println!("{:?}", pieces);
+    /// Hands an unchoked peer the pieces the picker chooses among those it has.
+    fn assign(&mut self, address: SocketAddr) {
+        loop {
+            let Some(peer) = self.peers.get(&address) else {
+                return;
+            };
+
+            if peer.choked || peer.assigned.len() >= PIECES_PER_PEER {
+                return;
+            }
+
+            let index = match self.picker.pick(&peer.pieces) {
+                Some(index) => index,
+                None if self.picker.is_endgame() => match self.pick_duplicate(peer) {
+                    Some(index) => index,
+                    None => return,
+                },
+                None => return,
+            };
+
+            let (data, blocks) = self.partial.remove(&index).unwrap_or_else(|| {
+                let length = self.piece_size(index);
+
+                (vec![0; length as usize], vec![false; block_count(length)])
+            });
+
+            let peer = self.peers.get_mut(&address).unwrap();
+
+            if let Err(err) = peer.commands.send(ConnectionCommand::Download {
+                index,
+                data,
+                blocks,
+            }) {
+                // The connection is gone; its `Disconnected` is on the way.
+                if let ConnectionCommand::Download { data, blocks, .. } = err.0 {
+                    self.release(index, data, blocks);
                 }
-                ConnectionMessage::PieceRecieved(_, _) => todo!(),
+
+                return;
             }
+
+            peer.assigned.insert(index);
         }
+    }
 
-        // TODO: Initial connections find out which peers are available
-        //
+    fn assign_all(&mut self) {
+        let addresses = self.peers.keys().copied().collect::<Vec<_>>();
+
+        for address in addresses {
+            self.assign(address);
+        }
+    }
+
+    /// Forgets a peer entirely, returning its pieces to the pool.
+    fn remove(&mut self, address: SocketAddr) {
+        if let Some(peer) = self.peers.remove(&address) {
+            // Blocks of these went to the connection with the assignment and are lost with it.
+            for index in peer.assigned {
+                self.release(index, vec![], vec![]);
+            }
+
+            for index in peer.pieces {
+                self.picker.remove_available(index);
+            }
+        }
+    }
+
+    /// Records a bad piece against the peer's address, banning it at the threshold. Dropping its
+    /// command channel makes the connection shut down.
+    fn blame(&mut self, address: SocketAddr) {
+        let strikes = self.strikes.entry(address.ip()).or_default();
+        *strikes += 1;
+
+        if *strikes < self.ban_threshold {
+            return;
+        }
+
+        eprintln!("{} -> banned after {} bad pieces", address, strikes);
+        self.banned.insert(address.ip());
+
+        let banned = self
+            .peers
+            .keys()
+            .filter(|peer| peer.ip() == address.ip())
+            .copied()
+            .collect::<Vec<_>>();
+
+        for peer in banned {
+            self.remove(peer);
+        }
+    }
+
+    /// Fails only when a verified piece can't be written.
+    fn handle(&mut self, address: SocketAddr, message: ConnectionMessage) -> std::io::Result<()> {
+        match message {
+            ConnectionMessage::PiecesAvailable(pieces) => {
+                if let Some(peer) = self.peers.get_mut(&address) {
+                    for index in pieces {
+                        if index < self.piece_hashes.len() && peer.pieces.insert(index) {
+                            self.picker.add_available(index);
+                        }
+                    }
+                }
+
+                self.assign(address);
+            }
+            ConnectionMessage::PieceRecieved(index, data) => {
+                let Some(peer) = self.peers.get_mut(&address) else {
+                    return Ok(());
+                };
+
+                // Anything not assigned to this peer may have been handed to another since.
+                if !peer.assigned.remove(&index) {
+                    return Ok(());
+                }
+
+                self.stats.add_downloaded(data.len() as u64);
+
+                let mut contributors = self.contributors.remove(&index).unwrap_or_default();
+                contributors.insert(address);
+
+                if Sha1::digest(&data).as_slice() == self.piece_hashes[index] {
+                    if let Some(storage) = &self.storage {
+                        storage.write_piece(index, &data)?;
+                    }
+
+                    self.picker.complete(index);
+                    self.stats.complete_bytes(data.len() as u64);
+
+                    // Endgame duplicates of this piece are no longer needed.
+                    let mut cancelled = false;
+
+                    for peer in self.peers.values_mut() {
+                        if peer.assigned.remove(&index) {
+                            let _ = peer.commands.send(ConnectionCommand::Cancel { index });
+                            cancelled = true;
+                        }
+                    }
+
+                    if cancelled {
+                        self.assign_all();
+                    } else {
+                        self.assign(address);
+                    }
+                } else {
+                    // Back in the pool unless a duplicate is still on its way; `assign_all`
+                    // hands it to whoever can fetch it.
+                    eprintln!("{} -> piece {} failed verification", address, index);
+                    self.release(index, vec![], vec![]);
+
+                    for contributor in contributors {
+                        self.blame(contributor);
+                    }
+
+                    self.assign_all();
+                }
+            }
+            ConnectionMessage::PieceReleased(index, data, blocks) => {
+                if let Some(peer) = self.peers.get_mut(&address)
+                    && peer.assigned.remove(&index)
+                {
+                    // Blocks from an endgame duplicate are dropped; the other peer keeps going.
+                    if self.holders(index) == 0 && blocks.iter().any(|block| *block) {
+                        self.contributors.entry(index).or_default().insert(address);
+                    }
+
+                    self.release(index, data, blocks);
+                    self.assign_all();
+                }
+            }
+            ConnectionMessage::PieceRejected(index) => {
+                if let Some(peer) = self.peers.get_mut(&address)
+                    && peer.pieces.remove(&index)
+                {
+                    self.picker.remove_available(index);
+                }
+            }
+            ConnectionMessage::Choked => {
+                if let Some(peer) = self.peers.get_mut(&address) {
+                    peer.choked = true;
+                }
+            }
+            ConnectionMessage::Unchoked => {
+                if let Some(peer) = self.peers.get_mut(&address) {
+                    peer.choked = false;
+                }
+
+                self.assign(address);
+            }
+            ConnectionMessage::Disconnected => {
+                // Lets a later announce reconnect it, unless its address was banned.
+                self.known_peers.remove(&address);
+                self.remove(address);
+                self.assign_all();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs until interrupted, or until a verified piece fails to be written.
+    pub async fn download(mut self, peers: &[Peer]) -> std::io::Result<()> {
+        self.connect(peers);
+
+        let mut completed = self.picker.is_complete();
+        let mut result = Ok(());
+
+        loop {
+            tokio::select! {
+                msg = self.rx.recv() => match msg {
+                    Some((address, message)) => {
+                        result = self.handle(address, message);
+
+                        if result.is_err() {
+                            break;
+                        }
+                    }
+                    None => break,
+                },
+                Some(peers) = self.announcer.next_peers() => self.connect(&peers),
+                _ = tokio::signal::ctrl_c() => break,
+            }
+
+            if !completed && self.picker.is_complete() {
+                completed = true;
+                println!("Download complete.");
+                self.announcer.completed().await;
+            }
+        }
 
-        // TODO: Determine peer selection strategy (random first)
+        self.announcer.stop().await;
 
-        // TODO: Delegate peice downloading to connncetions
+        result
     }
 }
@@ -0,0 +1,320 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    bencode,
+    torrent::{FileEntry, Info, TorrentFile},
+};
+
+pub const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+pub const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+/// Piece count the automatic piece length aims for; keeps .torrent files reasonably small.
+const TARGET_PIECE_COUNT: u64 = 1500;
+
+/// Smallest power of two (within the min/max bounds) that keeps the piece count near the target.
+pub fn default_piece_length(total_length: u64) -> u64 {
+    (total_length / TARGET_PIECE_COUNT)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// Creates a .torrent from a file or directory on disk.
+pub struct TorrentBuilder {
+    path: PathBuf,
+    piece_length: Option<u64>,
+    trackers: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    private: bool,
+    web_seeds: Vec<String>,
+}
+
+impl TorrentBuilder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            piece_length: None,
+            trackers: vec![],
+            comment: None,
+            created_by: Some(format!(
+                "{}/{}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            )),
+            creation_date: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|it| it.as_secs() as i64),
+            private: false,
+            web_seeds: vec![],
+        }
+    }
+
+    pub fn with_piece_length(mut self, piece_length: u64) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Adds a tier of trackers; the first tracker of the first tier becomes `announce`.
+    pub fn with_tier(mut self, tier: Vec<String>) -> Self {
+        if !tier.is_empty() {
+            self.trackers.push(tier);
+        }
+        self
+    }
+
+    pub fn with_comment(mut self, comment: String) -> Self {
+        self.comment = Some(comment);
+        self
+    }
+
+    pub fn with_created_by(mut self, created_by: Option<String>) -> Self {
+        self.created_by = created_by;
+        self
+    }
+
+    pub fn with_creation_date(mut self, creation_date: Option<i64>) -> Self {
+        self.creation_date = creation_date;
+        self
+    }
+
+    pub fn with_private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    pub fn with_web_seed(mut self, url: String) -> Self {
+        self.web_seeds.push(url);
+        self
+    }
+
+    pub fn build(self) -> Result<TorrentFile, String> {
+        let name = self
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(format!("Can't derive a torrent name from {:?}.", self.path))?
+            .to_string();
+
+        let metadata = std::fs::metadata(&self.path).map_err(|err| err.to_string())?;
+
+        if !metadata.is_dir() && !metadata.is_file() {
+            return Err(format!(
+                "{:?} is neither a file nor a directory.",
+                self.path
+            ));
+        }
+
+        let (files, entries) = if metadata.is_dir() {
+            let mut files = vec![];
+            collect_files(&self.path, &mut files)?;
+            files.sort();
+
+            if files.is_empty() {
+                return Err(format!("{:?} does not contain any files.", self.path));
+            }
+
+            let entries = files
+                .iter()
+                .map(|(path, length)| {
+                    Ok(FileEntry {
+                        length: *length,
+                        path: relative_components(&self.path, path)?,
+                    })
+                })
+                .collect::<Result<Vec<FileEntry>, String>>()?;
+
+            (files, Some(entries))
+        } else {
+            (vec![(self.path.clone(), metadata.len())], None)
+        };
+
+        let total_length = files.iter().map(|(_, length)| length).sum::<u64>();
+        let piece_length = self
+            .piece_length
+            .unwrap_or_else(|| default_piece_length(total_length));
+
+        if !piece_length.is_power_of_two() {
+            return Err(format!(
+                "Piece length {} is not a power of two.",
+                piece_length
+            ));
+        }
+
+        if !(MIN_PIECE_LENGTH..=MAX_PIECE_LENGTH).contains(&piece_length) {
+            return Err(format!(
+                "Piece length {} is outside {}..={} bytes.",
+                piece_length, MIN_PIECE_LENGTH, MAX_PIECE_LENGTH
+            ));
+        }
+
+        let info = Info {
+            name,
+            piece_length,
+            pieces: hash_pieces(&files, total_length, piece_length)?,
+            length: entries.is_none().then_some(total_length),
+            files: entries,
+            private: self.private.then_some(1),
+        };
+
+        let info_raw = bencode::to_bytes(&info)?;
+
+        let announce = self
+            .trackers
+            .first()
+            .and_then(|tier| tier.first())
+            .cloned()
+            .unwrap_or_default();
+
+        let tracker_count = self.trackers.iter().map(Vec::len).sum::<usize>();
+
+        Ok(TorrentFile {
+            announce,
+            announce_list: (tracker_count > 1).then_some(self.trackers),
+            comment: self.comment,
+            created_by: self.created_by,
+            creation_date: self.creation_date,
+            url_list: (!self.web_seeds.is_empty()).then_some(self.web_seeds),
+            info,
+            info_raw,
+        })
+    }
+}
+
+/// Every regular file below `directory`, with its size.
+fn collect_files(directory: &Path, files: &mut Vec<(PathBuf, u64)>) -> Result<(), String> {
+    for entry in std::fs::read_dir(directory).map_err(|err| err.to_string())? {
+        let entry = entry.map_err(|err| err.to_string())?;
+        let file_type = entry.file_type().map_err(|err| err.to_string())?;
+
+        // Symlinks aren't followed, as one pointing at an ancestor would recurse forever; FIFOs,
+        // sockets and devices would block the hashing threads. All of them are left out.
+        if file_type.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            let metadata = entry.metadata().map_err(|err| err.to_string())?;
+            files.push((entry.path(), metadata.len()));
+        }
+    }
+
+    Ok(())
+}
+
+fn relative_components(root: &Path, path: &Path) -> Result<Vec<String>, String> {
+    path.strip_prefix(root)
+        .map_err(|err| err.to_string())?
+        .components()
+        .map(|component| {
+            component
+                .as_os_str()
+                .to_str()
+                .map(String::from)
+                .ok_or(format!("{:?} is not valid UTF-8.", path))
+        })
+        .collect()
+}
+
+/// Hashes every piece, splitting the piece range evenly across the available cores. Pieces may
+/// straddle file boundaries, so each worker reads through the concatenated file list.
+fn hash_pieces(
+    files: &[(PathBuf, u64)],
+    total_length: u64,
+    piece_length: u64,
+) -> Result<Vec<u8>, String> {
+    let piece_count = total_length.div_ceil(piece_length) as usize;
+    let mut pieces = vec![0u8; piece_count * 20];
+
+    let workers = thread::available_parallelism()
+        .map(|it| it.get())
+        .unwrap_or(1);
+    let per_worker = piece_count.div_ceil(workers).max(1);
+
+    thread::scope(|scope| {
+        let handles = pieces
+            .chunks_mut(per_worker * 20)
+            .enumerate()
+            .map(|(worker, hashes)| {
+                scope.spawn(move || -> std::io::Result<()> {
+                    // Small inputs only ever fill a fraction of a large piece.
+                    let mut buffer = vec![0u8; piece_length.min(total_length) as usize];
+
+                    for (index, hash) in hashes.chunks_mut(20).enumerate() {
+                        let offset = (worker * per_worker + index) as u64 * piece_length;
+                        let length = piece_length.min(total_length - offset) as usize;
+
+                        read_range(files, offset, &mut buffer[..length])?;
+                        hash.copy_from_slice(&Sha1::digest(&buffer[..length]));
+                    }
+
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .try_for_each(|handle| match handle.join() {
+                Ok(result) => result.map_err(|err| err.to_string()),
+                Err(_) => Err(String::from("Hashing thread panicked.")),
+            })
+    })?;
+
+    Ok(pieces)
+}
+
+fn read_range(files: &[(PathBuf, u64)], offset: u64, buffer: &mut [u8]) -> std::io::Result<()> {
+    let mut file_start = 0;
+    let mut filled = 0;
+
+    for (path, length) in files {
+        let file_end = file_start + length;
+        let position = offset + filled as u64;
+
+        if filled < buffer.len() && position < file_end {
+            let take = ((file_end - position) as usize).min(buffer.len() - filled);
+
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(position - file_start))?;
+            file.read_exact(&mut buffer[filled..filled + take])?;
+
+            filled += take;
+        }
+
+        file_start = file_end;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_symlinks_and_special_files() {
+        let root = std::env::temp_dir().join(format!("create-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        std::fs::write(root.join("dir/file"), b"hello").unwrap();
+        std::os::unix::fs::symlink(&root, root.join("dir/loop")).unwrap();
+        std::os::unix::fs::symlink(root.join("dir/file"), root.join("link")).unwrap();
+        let _socket = std::os::unix::net::UnixListener::bind(root.join("socket")).unwrap();
+
+        let torrent = TorrentBuilder::new(&root).build();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let files = torrent.unwrap().info.files.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, ["dir", "file"]);
+        assert_eq!(files[0].length, 5);
+    }
+}
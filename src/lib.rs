@@ -0,0 +1,13 @@
+pub mod announcer;
+pub mod bencode;
+pub mod connection;
+pub mod connection_manager;
+pub mod create;
+pub mod magnet;
+pub mod metadata;
+pub mod piece_picker;
+pub mod storage;
+pub mod torrent;
+pub mod tracker;
+pub mod tracker_server;
+pub mod udp_tracker;
@@ -0,0 +1,170 @@
+use std::str::FromStr;
+
+use crate::tracker::percent_decode;
+
+/// A parsed `magnet:?xt=urn:btih:...` link. Only the info hash is required; everything else
+/// is a hint for finding peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+    /// Direct peer addresses (`x.pe`) as `host:port`.
+    pub peers: Vec<String>,
+    pub web_seeds: Vec<String>,
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> Result<Self, String> {
+        let query = uri
+            .strip_prefix("magnet:?")
+            .ok_or(String::from("Error parsing Magnet, not a magnet link."))?;
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = vec![];
+        let mut peers = vec![];
+        let mut web_seeds = vec![];
+
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+
+            match key {
+                "xt" => {
+                    // Other `xt` URNs (btmh for v2, ed2k, ...) may appear alongside; skip them.
+                    if let Some(hash) = decode(value, false)?.strip_prefix("urn:btih:") {
+                        info_hash =
+                            Some(parse_info_hash(hash).ok_or(format!(
+                                "Error parsing Magnet, invalid info hash {}.",
+                                hash
+                            ))?);
+                    }
+                }
+                "dn" => display_name = Some(decode(value, true)?),
+                "tr" => trackers.push(decode(value, false)?),
+                "x.pe" => peers.push(decode(value, false)?),
+                "ws" => web_seeds.push(decode(value, false)?),
+                _ => {}
+            }
+        }
+
+        Ok(Magnet {
+            info_hash: info_hash.ok_or(String::from(
+                "Error parsing Magnet, missing urn:btih info hash.",
+            ))?,
+            display_name,
+            trackers,
+            peers,
+            web_seeds,
+        })
+    }
+}
+
+impl FromStr for Magnet {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Self::parse(value)
+    }
+}
+
+/// Parses an info hash written as 40 hex digits or, as in older magnet links, 32 base32 ones.
+pub fn parse_info_hash(hash: &str) -> Option<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 if hash.bytes().all(|byte| byte.is_ascii_hexdigit()) => (0..40)
+            .step_by(2)
+            .map(|index| u8::from_str_radix(hash.get(index..index + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>(),
+        32 => base32_decode(hash),
+        _ => None,
+    };
+
+    bytes?.try_into().ok()
+}
+
+/// RFC 4648 base32 without padding, as used by older magnet links.
+fn base32_decode(value: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(value.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for char in value.bytes() {
+        let digit = match char.to_ascii_uppercase() {
+            letter @ b'A'..=b'Z' => letter - b'A',
+            digit @ b'2'..=b'7' => digit - b'2' + 26,
+            _ => return None,
+        };
+
+        buffer = (buffer << 5) | digit as u32;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    Some(bytes)
+}
+
+fn decode(value: &str, plus_as_space: bool) -> Result<String, String> {
+    let bytes = percent_decode(value, plus_as_space)
+        .ok_or(format!("Error parsing Magnet, bad escape in {}.", value))?;
+
+    String::from_utf8(bytes).map_err(|_| format!("Error parsing Magnet, {} is not UTF-8.", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn parses_a_magnet_link() {
+        let magnet = Magnet::parse(&format!(
+            "magnet:?xt=urn:btih:{}&dn=a+b%20c&tr=http%3A%2F%2Ftracker&x.pe=10.0.0.1:6881",
+            HASH
+        ))
+        .unwrap();
+
+        assert_eq!(magnet.info_hash[..2], [0x01, 0x23]);
+        assert_eq!(magnet.display_name.as_deref(), Some("a b c"));
+        assert_eq!(magnet.trackers, ["http://tracker"]);
+        assert_eq!(magnet.peers, ["10.0.0.1:6881"]);
+    }
+
+    #[test]
+    fn rejects_malformed_links() {
+        for uri in [
+            String::from("http://example.com"),
+            String::from("magnet:?dn=name"),
+            String::from("magnet:?xt=urn:btih:0123"),
+            format!("magnet:?xt=urn:btih:{}&dn=%4", HASH),
+            format!("magnet:?xt=urn:btih:{}&dn=%+1", HASH),
+            format!("magnet:?xt=urn:btih:{}&dn=%C3", HASH),
+            format!("magnet:?xt=urn:btih:{}", HASH.replace('0', "g")),
+        ] {
+            assert!(Magnet::parse(&uri).is_err(), "{}", uri);
+        }
+    }
+
+    #[test]
+    fn rejects_non_ascii_info_hashes() {
+        // 40 bytes, but the two-byte `é` puts char boundaries off the hex digit pairs.
+        let uri = format!("magnet:?xt=urn:btih:a%C3%A9{}", &HASH[..37]);
+
+        assert!(Magnet::parse(&uri).is_err());
+        assert_eq!(parse_info_hash(&format!("aé{}", &HASH[..37])), None);
+    }
+
+    #[test]
+    fn parses_base32_info_hashes() {
+        assert_eq!(
+            parse_info_hash("AAAQEAYEAUDAOCAJBIFQYDIOB4IBCEQT"),
+            Some(std::array::from_fn(|index| index as u8))
+        );
+        assert_eq!(parse_info_hash("AAAQEAYEAUDAOCAJBIFQYDIOB4IBCEQ1"), None);
+    }
+}
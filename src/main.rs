@@ -1,186 +1,569 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use futures::{StreamExt, stream::FuturesUnordered};
 use nanoid::nanoid;
 use sha1::{Digest, Sha1};
+use tokio::net::{TcpListener, UdpSocket, lookup_host};
 
-use crate::{
-    bencode::{Bencode, BencodedDictionary},
-    connection_manager::ConnectionManager,
-    tracker::{Peer, TrackerRequest, TrackerResponse},
+use bittorent_protocol::{
+    announcer::{Announcer, TransferStats},
+    bencode::{self, Bencode, BinaryEncoding, to_hex},
+    connection::DEFAULT_PIPELINE_DEPTH,
+    connection_manager::{ConnectionManager, DEFAULT_BAN_THRESHOLD},
+    create::TorrentBuilder,
+    magnet::{Magnet, parse_info_hash},
+    metadata::fetch_metadata,
+    storage::Storage,
+    torrent::TorrentFile,
+    tracker::{AddressFamily, Peer, TrackerRequest, TrackerTiers, scrape},
+    tracker_server::TrackerServer,
 };
 
-mod bencode;
-mod connection;
-mod connection_manager;
-mod tracker;
+fn perform_hashing(candidate: &[u8]) -> [u8; 20] {
+    Sha1::digest(candidate).into()
+}
+
+const DEFAULT_TORRENT: &str = "./torrents/ubuntu-25.10-desktop-amd64.iso.torrent";
+
+const USAGE: &str = "Usage:
+    bittorent-protocol [--ip any|v4|v6|prefer-v4|prefer-v6] [--pipeline <requests>]
+        [--ban-threshold <pieces>] [-o <directory>] [torrent file]
+    bittorent-protocol [--ip any|v4|v6|prefer-v4|prefer-v6] [--pipeline <requests>]
+        [--ban-threshold <pieces>] [-o <directory>] <magnet link>
+    bittorent-protocol bencode dump [--json] [--base64] <file>
+    bittorent-protocol create <path> [-o <output>] [--announce <url>[,<url>...]]...
+        [--piece-length <bytes>] [--comment <text>] [--private] [--web-seed <url>]...
+        [--no-date] [--no-created-by]
+    bittorent-protocol scrape [--tracker <url>]... <torrent file | magnet link | info hash>...
+    bittorent-protocol tracker [--http <address>] [--udp <address>] [--interval <seconds>]
+        [--min-interval <seconds>] [--whitelist <info hash>[,<info hash>...]]";
+
+fn bencode_command(args: &[String]) -> Result<(), String> {
+    let [command, rest @ ..] = args else {
+        return Err(USAGE.to_string());
+    };
+
+    if command != "dump" {
+        return Err(USAGE.to_string());
+    }
+
+    let json = rest.iter().any(|arg| arg == "--json");
+    let encoding = if rest.iter().any(|arg| arg == "--base64") {
+        BinaryEncoding::Base64
+    } else {
+        BinaryEncoding::Hex
+    };
 
-#[derive(Debug)]
-struct TorrentFile {
-    announce: String,
-    info: Info,
-    info_raw: Vec<u8>,
+    let Some(path) = rest.iter().find(|arg| !arg.starts_with("--")) else {
+        return Err(USAGE.to_string());
+    };
+
+    let file = std::fs::read(path).map_err(|err| format!("Can't open {}: {}", path, err))?;
+
+    if json {
+        let value = Bencode::decode(&file)?.to_json(encoding);
+
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&value).map_err(|err| err.to_string())?
+        );
+    } else {
+        print!("{}", Bencode::dump(&file)?);
+    }
+
+    Ok(())
 }
 
-impl TryFrom<BencodedDictionary> for TorrentFile {
-    type Error = String;
+fn create_command(args: &[String]) -> Result<(), String> {
+    let mut args = args.iter();
+    let mut path = None;
+    let mut output = None;
+    let mut builder_options = vec![];
 
-    fn try_from(value: BencodedDictionary) -> Result<Self, Self::Error> {
-        if !value.contains_key("announce") || !value.contains_key("info") {
-            return Err(String::from("Error parsing TorrentFile, not valid."));
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or(USAGE.to_string());
+
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(value()?),
+            "--announce" | "--piece-length" | "--comment" | "--web-seed" => {
+                builder_options.push((arg.clone(), Some(value()?)))
+            }
+            "--private" | "--no-date" | "--no-created-by" => {
+                builder_options.push((arg.clone(), None))
+            }
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg.clone()),
+            _ => return Err(USAGE.to_string()),
         }
+    }
 
-        let (info, raw) = value.get("info").unwrap().try_into_dict()?;
+    let path = path.ok_or(USAGE.to_string())?;
+    let mut builder = TorrentBuilder::new(&path);
 
-        Ok(TorrentFile {
-            announce: value.get("announce").unwrap().try_into_string()?,
-            info: Info::try_from(info)?,
-            info_raw: raw,
-        })
+    for (option, value) in builder_options {
+        let value = value.unwrap_or_default();
+
+        builder = match option.as_str() {
+            "--announce" => builder.with_tier(value.split(',').map(String::from).collect()),
+            "--piece-length" => builder.with_piece_length(
+                value
+                    .parse()
+                    .map_err(|_| format!("Invalid piece length {}.", value))?,
+            ),
+            "--comment" => builder.with_comment(value),
+            "--web-seed" => builder.with_web_seed(value),
+            "--private" => builder.with_private(true),
+            "--no-date" => builder.with_creation_date(None),
+            _ => builder.with_created_by(None),
+        };
     }
-}
 
-#[derive(Debug)]
-struct Info {
-    name: String,
-    piece_length: u64,
-    pieces: Vec<u8>,
-    length: Option<u64>,
-    files: Option<Files>,
+    let torrent = builder.build()?;
+    let output = output.unwrap_or(format!("{}.torrent", torrent.info.name));
+
+    std::fs::write(&output, bencode::to_bytes(&torrent)?).map_err(|err| err.to_string())?;
+
+    let info_hash = to_hex(&perform_hashing(&torrent.info_raw));
+    println!("Wrote {} (info hash {})", output, info_hash);
+
+    Ok(())
 }
 
-impl TryFrom<BencodedDictionary> for Info {
-    type Error = String;
+/// Scrapes every target at each tracker given with `--tracker`, or otherwise at the trackers
+/// listed by the targets themselves.
+async fn scrape_command(args: &[String]) -> Result<(), String> {
+    let mut args = args.iter();
+    let mut trackers = vec![];
+    let mut target_trackers = vec![];
+    let mut info_hashes = vec![];
 
-    fn try_from(value: BencodedDictionary) -> Result<Self, Self::Error> {
-        if !value.contains_key("name")
-            || !value.contains_key("piece length")
-            || !value.contains_key("pieces")
-        {
-            return Err(String::from("Error parsing Info, not valid."));
-        }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tracker" => trackers.push(args.next().cloned().ok_or(USAGE.to_string())?),
+            _ if arg.starts_with('-') => return Err(USAGE.to_string()),
+            _ if arg.starts_with("magnet:") => {
+                let magnet = Magnet::parse(arg)?;
 
-        Ok(Info {
-            name: value.get("name").unwrap().try_into_string()?,
-            piece_length: value.get("piece length").unwrap().try_into_int()?,
-            pieces: value.get("pieces").unwrap().try_into_string_vec()?,
-            length: match value.get("length") {
-                Some(val) => Some(val.try_into_int()?),
-                None => None,
-            },
-            files: match value.get("files") {
-                Some(val) => {
-                    let (files, _) = val.try_into_dict()?;
-                    Some(Files::try_from(files)?)
+                info_hashes.push(magnet.info_hash);
+                target_trackers.extend(magnet.trackers);
+            }
+            _ => match parse_info_hash(arg) {
+                Some(info_hash) => info_hashes.push(info_hash),
+                None => {
+                    let file =
+                        std::fs::read(arg).map_err(|err| format!("Can't open {}: {}", arg, err))?;
+                    let torrent = TorrentFile::from_bytes(&file)?;
+                    let tiers =
+                        TrackerTiers::new(&torrent.announce, torrent.announce_list.as_ref());
+                    info_hashes.push(perform_hashing(&torrent.info_raw));
+                    target_trackers.extend(tiers.tiers().iter().flatten().cloned());
                 }
-                None => None,
             },
-        })
+        }
+    }
+
+    if info_hashes.is_empty() {
+        return Err(USAGE.to_string());
     }
-}
 
-#[derive(Debug)]
-struct Files {
-    length: u64,
-    path: String,
+    if trackers.is_empty() {
+        trackers = target_trackers;
+    }
+
+    let mut seen = HashSet::new();
+    trackers.retain(|tracker| seen.insert(tracker.clone()));
+
+    for tracker in trackers {
+        match scrape(&tracker, &info_hashes).await {
+            Ok(files) => {
+                for info_hash in &info_hashes {
+                    let hash = to_hex(info_hash);
+
+                    match files.get(info_hash) {
+                        Some(stats) => println!(
+                            "{} {} seeders {} leechers {} completed {}",
+                            tracker, hash, stats.complete, stats.incomplete, stats.downloaded
+                        ),
+                        None => println!("{} {} unknown torrent", tracker, hash),
+                    }
+                }
+            }
+            Err(err) => println!("{} -> {}", tracker, err),
+        }
+    }
+
+    Ok(())
 }
 
-impl TryFrom<BencodedDictionary> for Files {
-    type Error = String;
+/// Runs the embedded tracker until interrupted. Both front ends listen on port 6969 unless told
+/// otherwise.
+async fn tracker_command(args: &[String]) -> Result<(), String> {
+    let mut args = args.iter();
+    let mut http = String::from("0.0.0.0:6969");
+    let mut udp = String::from("0.0.0.0:6969");
+    let mut server = TrackerServer::new();
 
-    fn try_from(value: BencodedDictionary) -> Result<Self, Self::Error> {
-        if !value.contains_key("length") || !value.contains_key("path") {
-            return Err(String::from("Error parsing Files, not valid."));
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or(USAGE.to_string());
+
+        match arg.as_str() {
+            "--http" => http = value()?,
+            "--udp" => udp = value()?,
+            "--interval" => {
+                let value = value()?;
+                server = server.with_interval(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid interval {}.", value))?,
+                );
+            }
+            "--min-interval" => {
+                let value = value()?;
+                server = server.with_min_interval(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid interval {}.", value))?,
+                );
+            }
+            "--whitelist" => {
+                let value = value()?;
+                server = server.with_whitelist(
+                    value
+                        .split(',')
+                        .map(|hash| {
+                            parse_info_hash(hash).ok_or(format!("Invalid info hash {}.", hash))
+                        })
+                        .collect::<Result<Vec<[u8; 20]>, String>>()?,
+                );
+            }
+            _ => return Err(USAGE.to_string()),
         }
+    }
 
-        Ok(Files {
-            length: value.get("length").unwrap().try_into_int()?,
-            path: value.get("path").unwrap().try_into_string()?,
-        })
+    let listener = TcpListener::bind(&http)
+        .await
+        .map_err(|err| format!("Can't listen on {}: {}", http, err))?;
+    let socket = UdpSocket::bind(&udp)
+        .await
+        .map_err(|err| format!("Can't listen on {}: {}", udp, err))?;
+
+    println!(
+        "Tracker listening on http://{}/announce and udp://{}",
+        http, udp
+    );
+
+    let result = tokio::select! {
+        result = server.serve_http(listener) => result,
+        result = server.serve_udp(socket) => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+
+    result.map_err(|err| err.to_string())
+}
+
+#[tokio::main]
+async fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+
+    match args.first().map(String::as_str) {
+        Some("bencode") => {
+            if let Err(err) = bencode_command(&args[1..]) {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        Some("create") => {
+            if let Err(err) = create_command(&args[1..]) {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        Some("scrape") => {
+            if let Err(err) = scrape_command(&args[1..]).await {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        Some("tracker") => {
+            if let Err(err) = tracker_command(&args[1..]).await {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        Some("-h" | "--help") => println!("{}", USAGE),
+        _ => match download_options(&args) {
+            Ok((Some(uri), options)) if uri.starts_with("magnet:") => {
+                download_magnet(uri, options).await
+            }
+            Ok((path, options)) => download(path.unwrap_or(DEFAULT_TORRENT), options).await,
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        },
     }
 }
 
-fn parse_file(file: Vec<u8>) -> Result<TorrentFile, String> {
-    let decoded_dictionary = Bencode::decode_dict(file);
+#[derive(Debug, Clone)]
+struct DownloadOptions {
+    family: AddressFamily,
+    pipeline_depth: usize,
+    ban_threshold: u32,
+    /// Directory the torrent's files are written under.
+    output: PathBuf,
+}
 
-    TorrentFile::try_from(decoded_dictionary)
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            family: AddressFamily::default(),
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            ban_threshold: DEFAULT_BAN_THRESHOLD,
+            output: PathBuf::from("."),
+        }
+    }
 }
 
-fn perform_hashing(candidate: Vec<u8>) -> (Vec<u8>, String) {
-    let mut hasher = Sha1::new();
+fn download_options(args: &[String]) -> Result<(Option<&str>, DownloadOptions), String> {
+    let mut args = args.iter();
+    let mut target = None;
+    let mut options = DownloadOptions::default();
 
-    hasher.update(candidate);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--ip" => options.family = args.next().ok_or(USAGE.to_string())?.parse()?,
+            "--pipeline" => {
+                options.pipeline_depth = args
+                    .next()
+                    .ok_or(USAGE.to_string())?
+                    .parse()
+                    .map_err(|_| String::from("--pipeline expects a number of requests."))?
+            }
+            "--ban-threshold" => {
+                options.ban_threshold = args
+                    .next()
+                    .ok_or(USAGE.to_string())?
+                    .parse()
+                    .map_err(|_| String::from("--ban-threshold expects a number of pieces."))?
+            }
+            "-o" | "--output" => {
+                options.output = PathBuf::from(args.next().ok_or(USAGE.to_string())?)
+            }
+            _ if target.is_none() && !arg.starts_with('-') => target = Some(arg.as_str()),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
 
-    let result = hasher.finalize();
+    Ok((target, options))
+}
 
-    (
-        result.iter().map(|val| val.clone()).collect::<Vec<u8>>(),
-        result
-            .iter()
-            .map(|&byte| format!("%{:02x}", byte))
-            .collect::<String>(),
-    )
+fn generate_peer_id() -> String {
+    format!("-RS0001-{}", nanoid!(12))
 }
 
-#[tokio::main]
-async fn main() {
-    let file = std::fs::read("./torrents/ubuntu-25.10-desktop-amd64.iso.torrent")
-        .expect("Can't open torrent file.")
+async fn download(path: &str, options: DownloadOptions) {
+    let torrent = std::fs::read(path)
+        .map_err(|err| format!("Can't open torrent file {}: {}", path, err))
+        .and_then(|file| TorrentFile::from_bytes(&file));
+
+    match torrent {
+        Ok(torrent) => start(torrent, generate_peer_id(), options, vec![]).await,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn download_magnet(uri: &str, options: DownloadOptions) {
+    let magnet = match Magnet::parse(uri) {
+        Ok(magnet) => magnet,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let peer_id = generate_peer_id();
+
+    // Every tracker is announced to at once, and their peers are queued behind the `x.pe` ones
+    // as the answers come in, so a dead tracker never holds up the metadata. The size is unknown
+    // until the metadata arrives; any non-zero `left` keeps us a leecher.
+    let mut announces = magnet
+        .trackers
         .iter()
-        .map(|it| it.clone())
-        .collect::<Vec<u8>>();
-
-    let torrent = parse_file(file);
-
-    if let Ok(torr) = torrent {
-        let pieces = torr
-            .info
-            .pieces
-            .chunks(20)
-            .map(|chunk| {
-                chunk
-                    .iter()
-                    .map(|&byte| format!("%{:02x}", byte))
-                    .collect::<String>()
-            })
-            .collect::<Vec<String>>();
-
-        let (raw_info_hash, info_hash) = perform_hashing(torr.info_raw);
-
-        let peer_id = format!("-RS0001-{}", nanoid!(12));
-
-        let tracker_request = TrackerRequest::from(
-            torr.announce,
-            info_hash,
-            peer_id.clone(),
-            6881,
-            torr.info.length.unwrap(),
-        );
+        .map(|tracker| {
+            let request =
+                TrackerRequest::from(tracker.clone(), magnet.info_hash, peer_id.clone(), 6881, 1);
+
+            async move { (tracker, request.fetch_peer_info().await) }
+        })
+        .collect::<FuturesUnordered<_>>();
+
+    let mut peers = magnet.peers.clone();
+    let mut queue = peers.iter().cloned().collect::<VecDeque<_>>();
+    let mut fetch = None;
 
-        let response = tracker_request.fetch_peer_info().await;
-
-        if let Ok(resp) = response {
-            match resp {
-                TrackerResponse::Success(peer_info) => {
-                    let ip_v4_peers: Vec<Peer> = peer_info
-                        .peers
-                        .into_iter()
-                        .filter(|peer| !peer.ip.contains(":"))
-                        .collect();
-
-                    ConnectionManager::new(
-                        &ip_v4_peers,
-                        raw_info_hash,
-                        peer_id,
-                        pieces,
-                        peer_info.interval,
-                    )
-                    .await
-                    .download()
-                    .await;
+    let (peer, info_raw) = loop {
+        if fetch.is_none()
+            && let Some(peer) = queue.pop_front()
+        {
+            let info_hash = magnet.info_hash;
+            let peer_id = peer_id.clone();
+
+            fetch = Some(Box::pin(async move {
+                let result = fetch_metadata(&peer, &info_hash, peer_id.as_bytes()).await;
+                (peer, result)
+            }));
+        }
+
+        if fetch.is_none() && announces.is_empty() {
+            eprintln!("Could not fetch metadata from any peer.");
+            std::process::exit(1);
+        }
+
+        tokio::select! {
+            Some((tracker, result)) = announces.next(), if !announces.is_empty() => match result {
+                Ok(peer_info) => {
+                    for peer in options.family.apply(peer_info.peers) {
+                        let address = peer.address.to_string();
+
+                        if !peers.contains(&address) {
+                            peers.push(address.clone());
+                            queue.push_back(address);
+                        }
+                    }
                 }
+                Err(err) => println!("{} -> {}", tracker, err),
+            },
+            (peer, result) = async { fetch.as_mut().unwrap().await }, if fetch.is_some() => {
+                fetch = None;
 
-                TrackerResponse::Failure(err) => {
-                    println!("{:?}", err);
+                match result {
+                    Ok(info_raw) => break (peer, info_raw),
+                    Err(err) => println!("{} -> {}", peer, err),
                 }
             }
         }
+    };
+
+    // Announces still in flight are abandoned; the announcer starts over with the download.
+    drop(announces);
+
+    let announce = magnet.trackers.first().cloned().unwrap_or_default();
+
+    let mut torrent = match TorrentFile::from_info(announce, info_raw) {
+        Ok(torrent) => torrent,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    torrent.announce_list = Some(
+        magnet
+            .trackers
+            .iter()
+            .map(|tracker| vec![tracker.clone()])
+            .collect(),
+    );
+
+    if !magnet.web_seeds.is_empty() {
+        torrent.url_list = Some(magnet.web_seeds);
+    }
+
+    // The peer that served the metadata goes first, then `x.pe` and tracker peers.
+    peers.retain(|other| *other != peer);
+    peers.insert(0, peer);
+
+    let known = resolve_peers(&peers, options.family).await;
+
+    start(torrent, peer_id, options, known).await
+}
+
+/// Resolves `host:port` peer addresses, dropping those that don't resolve.
+async fn resolve_peers(addresses: &[String], family: AddressFamily) -> Vec<Peer> {
+    let mut peers = vec![];
+
+    for address in addresses {
+        if let Ok(Some(address)) = lookup_host(address.as_str())
+            .await
+            .map(|mut resolved| resolved.next())
+        {
+            peers.push(Peer {
+                peer_id: None,
+                address,
+            });
+        }
+    }
+
+    family.apply(peers)
+}
+
+/// Downloads `torr` from `peers` and whatever the trackers return.
+async fn start(torr: TorrentFile, peer_id: String, options: DownloadOptions, peers: Vec<Peer>) {
+    let piece_hashes = torr.info.piece_hashes();
+
+    let info_hash = perform_hashing(&torr.info_raw);
+
+    let trackers = TrackerTiers::new(&torr.announce, torr.announce_list.as_ref());
+
+    let tracker_request = TrackerRequest::from(
+        torr.announce,
+        info_hash,
+        peer_id.clone(),
+        6881,
+        torr.info.total_length(),
+    );
+
+    let stats = Arc::new(TransferStats::new(torr.info.total_length()));
+    if trackers.is_empty() && peers.is_empty() {
+        eprintln!("No trackers or peers to download from.");
+        return;
+    }
+
+    let storage = torr.info.file_paths(&options.output).and_then(|files| {
+        Storage::new(files, torr.info.piece_length)
+            .map_err(|err| format!("Can't create files in {:?}: {}", options.output, err))
+    });
+
+    let storage = match storage {
+        Ok(storage) => storage,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // Peers from the trackers reach the manager as each announce completes.
+    let announcer =
+        Announcer::new(trackers, tracker_request, stats.clone(), options.family).spawn();
+
+    let peer_id = peer_id
+        .as_bytes()
+        .try_into()
+        .expect("Peer id is 20 bytes long.");
+
+    let result = ConnectionManager::new(
+        info_hash,
+        peer_id,
+        piece_hashes,
+        torr.info.piece_length,
+        torr.info.total_length(),
+        stats,
+        announcer,
+    )
+    .with_pipeline_depth(options.pipeline_depth)
+    .with_ban_threshold(options.ban_threshold)
+    .with_storage(storage)
+    .download(&peers)
+    .await;
+
+    if let Err(err) = result {
+        eprintln!("Can't write to {:?}: {}", options.output, err);
+        std::process::exit(1);
     }
 }
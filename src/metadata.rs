@@ -0,0 +1,213 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_util::codec::Framed;
+
+use crate::{
+    bencode::{self, DecodeStatus, StreamDecoder},
+    connection::{CONNECT_TIMEOUT, Extensions, Handshake, PeerCodec, PeerMessage},
+};
+
+/*
+ * Fetches the info dictionary of a torrent from a peer using the extension protocol (BEP 10)
+ * and its `ut_metadata` extension (BEP 9), so a download can start from a magnet link.
+ */
+
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+pub const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+const EXTENDED_HANDSHAKE_ID: u8 = 0;
+/// The id we ask peers to use when sending us `ut_metadata` messages.
+const LOCAL_UT_METADATA_ID: u8 = 1;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct ExtendedHandshake {
+    m: BTreeMap<String, i64>,
+    metadata_size: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct MetadataMessage {
+    msg_type: u8,
+    piece: u32,
+    total_size: Option<u64>,
+}
+
+impl MetadataMessage {
+    const REQUEST: u8 = 0;
+    const DATA: u8 = 1;
+    const REJECT: u8 = 2;
+}
+
+/// Connects to `address` (`host:port`) and downloads the raw info dictionary, returning it only
+/// once its SHA-1 matches `info_hash`.
+pub async fn fetch_metadata(
+    address: &str,
+    info_hash: &[u8; 20],
+    peer_id: &[u8],
+) -> Result<Vec<u8>, String> {
+    let mut stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+        .await
+        .map_err(|_| format!("Timed out connecting to {}.", address))?
+        .map_err(|err| err.to_string())?;
+
+    timeout(FETCH_TIMEOUT, download(&mut stream, info_hash, peer_id))
+        .await
+        .map_err(|_| format!("Timed out fetching metadata from {}.", address))?
+}
+
+async fn download(
+    stream: &mut TcpStream,
+    info_hash: &[u8; 20],
+    peer_id: &[u8],
+) -> Result<Vec<u8>, String> {
+    let local = Handshake {
+        extensions: Extensions {
+            extended: true,
+            ..Extensions::default()
+        },
+        info_hash: *info_hash,
+        peer_id: peer_id
+            .try_into()
+            .map_err(|_| String::from("Peer id must be 20 bytes long."))?,
+    };
+
+    let remote = local
+        .exchange(stream)
+        .await
+        .map_err(|err| format!("Handshake failed: {}.", err))?;
+
+    if !remote.extensions.extended {
+        return Err(String::from(
+            "Peer does not support the extension protocol.",
+        ));
+    }
+
+    let mut stream = Framed::new(stream, PeerCodec::new());
+
+    let mut local = ExtendedHandshake::default();
+    local
+        .m
+        .insert(String::from("ut_metadata"), LOCAL_UT_METADATA_ID as i64);
+
+    send_extended(
+        &mut stream,
+        EXTENDED_HANDSHAKE_ID,
+        bencode::to_bytes(&local)?,
+    )
+    .await?;
+
+    let (remote_id, size) = loop {
+        let (id, payload) = read_extended(&mut stream).await?;
+
+        if id != EXTENDED_HANDSHAKE_ID {
+            continue;
+        }
+
+        let (remote, _) = decode_prefix::<ExtendedHandshake>(&payload)?;
+
+        match (remote.m.get("ut_metadata"), remote.metadata_size) {
+            (Some(&id), Some(size)) if id > 0 && id <= u8::MAX as i64 => {
+                break (id as u8, size as usize);
+            }
+            _ => return Err(String::from("Peer does not offer ut_metadata.")),
+        }
+    };
+
+    if size == 0 || size > MAX_METADATA_SIZE {
+        return Err(format!("Peer reported an invalid metadata size {}.", size));
+    }
+
+    let piece_count = size.div_ceil(METADATA_PIECE_SIZE);
+
+    for piece in 0..piece_count {
+        let request = MetadataMessage {
+            msg_type: MetadataMessage::REQUEST,
+            piece: piece as u32,
+            total_size: None,
+        };
+
+        send_extended(&mut stream, remote_id, bencode::to_bytes(&request)?).await?;
+    }
+
+    let mut metadata = vec![0u8; size];
+    let mut received = vec![false; piece_count];
+
+    while received.iter().any(|done| !done) {
+        let (id, payload) = read_extended(&mut stream).await?;
+
+        if id != LOCAL_UT_METADATA_ID {
+            continue;
+        }
+
+        let (message, consumed) = decode_prefix::<MetadataMessage>(&payload)?;
+        let piece = message.piece as usize;
+
+        match message.msg_type {
+            MetadataMessage::DATA if piece < piece_count => {
+                let start = piece * METADATA_PIECE_SIZE;
+                let end = (start + METADATA_PIECE_SIZE).min(size);
+                let data = &payload[consumed..];
+
+                if data.len() != end - start {
+                    return Err(format!("Metadata piece {} has the wrong length.", piece));
+                }
+
+                metadata[start..end].copy_from_slice(data);
+                received[piece] = true;
+            }
+            MetadataMessage::REJECT => {
+                return Err(format!("Peer rejected metadata piece {}.", piece));
+            }
+            _ => {}
+        }
+    }
+
+    if Sha1::digest(&metadata).as_slice() != info_hash {
+        return Err(String::from("Metadata does not match the info hash."));
+    }
+
+    Ok(metadata)
+}
+
+/// Decodes the bencoded dictionary at the start of `payload`; `ut_metadata` data messages carry
+/// the raw piece right after it.
+fn decode_prefix<T: serde::de::DeserializeOwned>(payload: &[u8]) -> Result<(T, usize), String> {
+    let mut decoder = StreamDecoder::new();
+    decoder.feed(payload);
+
+    match decoder.decode()? {
+        DecodeStatus::Complete(value, consumed) => Ok((bencode::from_state(value)?, consumed)),
+        DecodeStatus::NeedMoreData => Err(String::from("Truncated extension message.")),
+    }
+}
+
+async fn send_extended(
+    stream: &mut Framed<&mut TcpStream, PeerCodec>,
+    id: u8,
+    payload: Vec<u8>,
+) -> Result<(), String> {
+    stream
+        .send(PeerMessage::Extended { id, payload })
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// Reads messages until an extended one arrives, returning its extended id and payload.
+async fn read_extended(
+    stream: &mut Framed<&mut TcpStream, PeerCodec>,
+) -> Result<(u8, Vec<u8>), String> {
+    loop {
+        match stream.next().await {
+            Some(Ok(PeerMessage::Extended { id, payload })) => return Ok((id, payload)),
+            Some(Ok(_)) => {}
+            Some(Err(err)) => return Err(err.to_string()),
+            None => return Err(String::from("Peer closed the connection.")),
+        }
+    }
+}
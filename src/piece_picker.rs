@@ -0,0 +1,274 @@
+use std::collections::HashSet;
+
+use rand::seq::IteratorRandom;
+
+/*
+ * Decides which piece a peer should fetch next. Pieces already partially downloaded come first
+ * so their blocks don't sit around, then the first few pieces are picked at random so there is
+ * something to trade as soon as possible, after which the rarest piece in the swarm wins. Ties
+ * are broken at random so peers with the same view don't all converge on the same piece.
+ */
+
+/// Pieces picked at random before switching to rarest-first.
+pub const DEFAULT_RANDOM_FIRST: usize = 4;
+
+#[derive(Debug)]
+pub struct PiecePicker {
+    /// How many connected peers have each piece.
+    availability: Vec<u32>,
+    have: Vec<bool>,
+    completed: usize,
+    /// Pieces currently assigned to some peer.
+    in_progress: HashSet<usize>,
+    /// Pieces with some blocks downloaded and nobody working on them.
+    partial: HashSet<usize>,
+    random_first: usize,
+}
+
+impl PiecePicker {
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            availability: vec![0; piece_count],
+            have: vec![false; piece_count],
+            completed: 0,
+            in_progress: HashSet::new(),
+            partial: HashSet::new(),
+            random_first: DEFAULT_RANDOM_FIRST,
+        }
+    }
+
+    pub fn with_random_first(mut self, random_first: usize) -> Self {
+        self.random_first = random_first;
+        self
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.have.len()
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        self.have.get(index).copied().unwrap_or(false)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.completed == self.have.len()
+    }
+
+    /// Whether every missing piece is assigned to some peer, so idle peers may duplicate them.
+    pub fn is_endgame(&self) -> bool {
+        self.completed + self.in_progress.len() == self.have.len()
+    }
+
+    pub fn is_in_progress(&self, index: usize) -> bool {
+        self.in_progress.contains(&index)
+    }
+
+    pub fn availability(&self, index: usize) -> u32 {
+        self.availability.get(index).copied().unwrap_or(0)
+    }
+
+    /// Counts a peer announcing `index` through its bitfield or a Have message.
+    pub fn add_available(&mut self, index: usize) {
+        if let Some(count) = self.availability.get_mut(index) {
+            *count += 1;
+        }
+    }
+
+    /// Forgets a peer's piece, typically because the peer went away.
+    pub fn remove_available(&mut self, index: usize) {
+        if let Some(count) = self.availability.get_mut(index) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    /// Picks the next piece among those `peer_has` and marks it in progress.
+    pub fn pick(&mut self, peer_has: &HashSet<usize>) -> Option<usize> {
+        let candidates = peer_has.iter().copied().filter(|&index| {
+            index < self.have.len() && !self.have[index] && !self.in_progress.contains(&index)
+        });
+
+        let mut rng = rand::rng();
+
+        let partial = candidates
+            .clone()
+            .filter(|index| self.partial.contains(index));
+
+        let picked = match self.rarest(partial, &mut rng) {
+            Some(index) => Some(index),
+            None if self.completed < self.random_first => candidates.choose(&mut rng),
+            None => self.rarest(candidates, &mut rng),
+        }?;
+
+        self.in_progress.insert(picked);
+
+        Some(picked)
+    }
+
+    fn rarest(
+        &self,
+        candidates: impl Iterator<Item = usize> + Clone,
+        rng: &mut impl rand::Rng,
+    ) -> Option<usize> {
+        let rarest = candidates
+            .clone()
+            .map(|index| self.availability[index])
+            .min()?;
+
+        candidates
+            .filter(|&index| self.availability[index] == rarest)
+            .choose(rng)
+    }
+
+    /// Puts a piece back in the pool; `partial` says whether some of its blocks are kept.
+    pub fn release(&mut self, index: usize, partial: bool) {
+        self.in_progress.remove(&index);
+
+        if partial {
+            self.partial.insert(index);
+        } else {
+            self.partial.remove(&index);
+        }
+    }
+
+    /// Marks a verified piece as downloaded.
+    pub fn complete(&mut self, index: usize) {
+        self.in_progress.remove(&index);
+        self.partial.remove(&index);
+
+        if let Some(have) = self.have.get_mut(index)
+            && !*have
+        {
+            *have = true;
+            self.completed += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A picker past its random-first phase, with the given availability per piece.
+    fn picker(availability: &[u32]) -> PiecePicker {
+        let mut picker = PiecePicker::new(availability.len()).with_random_first(0);
+
+        for (index, count) in availability.iter().enumerate() {
+            for _ in 0..*count {
+                picker.add_available(index);
+            }
+        }
+
+        picker
+    }
+
+    fn all(count: usize) -> HashSet<usize> {
+        (0..count).collect()
+    }
+
+    #[test]
+    fn picks_the_rarest_piece_first() {
+        let mut picker = picker(&[3, 1, 2, 5]);
+
+        assert_eq!(picker.pick(&all(4)), Some(1));
+        assert_eq!(picker.pick(&all(4)), Some(2));
+        assert_eq!(picker.pick(&all(4)), Some(0));
+        assert_eq!(picker.pick(&all(4)), Some(3));
+        assert_eq!(picker.pick(&all(4)), None);
+    }
+
+    #[test]
+    fn only_picks_what_the_peer_has() {
+        let mut picker = picker(&[1, 2, 3]);
+
+        assert_eq!(picker.pick(&HashSet::from([1, 2, 7])), Some(1));
+        assert_eq!(picker.pick(&HashSet::from([1])), None);
+    }
+
+    #[test]
+    fn resumes_partial_pieces_before_rarer_ones() {
+        let mut picker = picker(&[1, 3, 4]);
+
+        assert_eq!(picker.pick(&HashSet::from([2])), Some(2));
+        picker.release(2, true);
+
+        assert_eq!(picker.pick(&all(3)), Some(2));
+        assert_eq!(picker.pick(&all(3)), Some(0));
+
+        // Released without blocks, a piece is just another candidate again.
+        picker.release(2, false);
+        assert_eq!(picker.pick(&all(3)), Some(1));
+    }
+
+    #[test]
+    fn picks_at_random_until_enough_pieces_complete() {
+        // Piece 0 is the rarest, so rarest-first would always start with it.
+        let bootstrap = || {
+            let mut picker = PiecePicker::new(64).with_random_first(1);
+
+            for index in 0..64 {
+                picker.add_available(index);
+
+                if index > 0 {
+                    picker.add_available(index);
+                }
+            }
+
+            picker
+        };
+
+        assert!((0..20).any(|_| bootstrap().pick(&all(64)) != Some(0)));
+
+        // Once the first piece completes, rarity decides.
+        let mut picker = bootstrap();
+        let first = picker.pick(&all(64)).unwrap();
+        picker.complete(first);
+
+        if first != 0 {
+            assert_eq!(picker.pick(&all(64)), Some(0));
+        }
+    }
+
+    #[test]
+    fn tracks_completion() {
+        let mut picker = picker(&[1, 1]);
+
+        assert!(!picker.is_complete());
+        picker.complete(0);
+        picker.complete(0);
+        assert!(picker.has(0) && !picker.has(1));
+        assert!(!picker.is_complete());
+
+        assert_eq!(picker.pick(&all(2)), Some(1));
+        picker.complete(1);
+        assert!(picker.is_complete());
+        assert_eq!(picker.pick(&all(2)), None);
+    }
+
+    #[test]
+    fn enters_endgame_once_every_missing_piece_is_assigned() {
+        let mut picker = picker(&[1, 1, 1]);
+
+        picker.complete(0);
+        assert_eq!(picker.pick(&HashSet::from([1])), Some(1));
+        assert!(!picker.is_endgame());
+
+        assert_eq!(picker.pick(&all(3)), Some(2));
+        assert!(picker.is_endgame());
+        assert!(picker.is_in_progress(1) && !picker.is_in_progress(0));
+
+        picker.release(2, false);
+        assert!(!picker.is_endgame());
+    }
+
+    #[test]
+    fn forgets_departed_peers() {
+        let mut picker = picker(&[1, 2]);
+
+        picker.remove_available(1);
+        picker.remove_available(1);
+        picker.remove_available(1);
+        assert_eq!(picker.availability(1), 0);
+
+        assert_eq!(picker.pick(&all(2)), Some(1));
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    fs::OpenOptions,
+    io::{Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+/*
+ * Writes verified pieces into the files of a torrent. The payload is the concatenation of every
+ * file in torrent order, so a piece may end in one file and continue in the next.
+ */
+
+#[derive(Debug)]
+pub struct Storage {
+    /// Every file with the offset of its first byte in the payload and its length.
+    files: Vec<(PathBuf, u64, u64)>,
+    piece_length: u64,
+}
+
+impl Storage {
+    /// Creates every file and its parent directories at full size, keeping existing contents.
+    pub fn new(files: Vec<(PathBuf, u64)>, piece_length: u64) -> std::io::Result<Self> {
+        let mut offset = 0;
+        let mut placed = vec![];
+
+        for (path, length) in files {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?
+                .set_len(length)?;
+
+            placed.push((path, offset, length));
+            offset += length;
+        }
+
+        Ok(Storage {
+            files: placed,
+            piece_length,
+        })
+    }
+
+    /// Writes piece `index` across the files it overlaps.
+    pub fn write_piece(&self, index: usize, mut data: &[u8]) -> std::io::Result<()> {
+        let mut offset = index as u64 * self.piece_length;
+
+        for (path, start, length) in &self.files {
+            if data.is_empty() {
+                break;
+            }
+
+            let end = start + length;
+
+            if offset >= end {
+                continue;
+            }
+
+            let count = (end - offset).min(data.len() as u64) as usize;
+
+            let mut file = OpenOptions::new().write(true).open(path)?;
+            file.seek(SeekFrom::Start(offset - start))?;
+            file.write_all(&data[..count])?;
+
+            data = &data[count..];
+            offset += count as u64;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_pieces_across_files() {
+        let root = std::env::temp_dir().join(format!("storage-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+
+        let files = vec![
+            (root.join("a"), 3),
+            (root.join("empty"), 0),
+            (root.join("dir/b"), 6),
+        ];
+        let storage = Storage::new(files, 4).unwrap();
+
+        // Out of order, as pieces arrive from peers.
+        storage.write_piece(2, b"8").unwrap();
+        storage.write_piece(0, b"0123").unwrap();
+        storage.write_piece(1, b"4567").unwrap();
+
+        let read = |name: &str| std::fs::read(root.join(name)).unwrap();
+        let (a, empty, b) = (read("a"), read("empty"), read("dir/b"));
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(a, b"012");
+        assert_eq!(empty, b"");
+        assert_eq!(b, b"345678");
+    }
+}
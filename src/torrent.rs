@@ -0,0 +1,364 @@
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    bencode::{self, Bencode},
+    create::MAX_PIECE_LENGTH,
+};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TorrentFile {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub announce: String,
+    #[serde(rename = "announce-list")]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    pub comment: Option<String>,
+    #[serde(rename = "created by")]
+    pub created_by: Option<String>,
+    #[serde(rename = "creation date")]
+    pub creation_date: Option<i64>,
+    /// Web seeds (BEP 19); written as a list, but a single string is accepted too.
+    #[serde(rename = "url-list", default, deserialize_with = "string_or_list")]
+    pub url_list: Option<Vec<String>>,
+    pub info: Info,
+    #[serde(skip)]
+    pub info_raw: Vec<u8>,
+}
+
+impl TorrentFile {
+    pub fn from_bytes(file: &[u8]) -> Result<Self, String> {
+        let decoded = Bencode::decode_ref(file)?;
+
+        // The info hash has to be computed over the exact bytes of the info dictionary, so keep
+        // its raw span around rather than re-encoding the parsed struct.
+        let info_raw = match decoded.get(b"info") {
+            Some(info) => info.raw().to_vec(),
+            None => return Err(String::from("Error parsing TorrentFile, not valid.")),
+        };
+
+        let mut torrent: TorrentFile = bencode::from_ref(decoded)?;
+        torrent.info_raw = info_raw;
+        torrent.info.validate()?;
+
+        Ok(torrent)
+    }
+
+    /// Builds a torrent around an info dictionary fetched from peers (e.g. for a magnet link);
+    /// the caller is responsible for having verified it against the info hash.
+    pub fn from_info(announce: String, info_raw: Vec<u8>) -> Result<Self, String> {
+        let info: Info = bencode::from_bytes(&info_raw)?;
+        info.validate()?;
+
+        Ok(TorrentFile {
+            announce,
+            announce_list: None,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            url_list: None,
+            info,
+            info_raw,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Info {
+    pub name: String,
+    #[serde(rename = "piece length")]
+    pub piece_length: u64,
+    #[serde(with = "serde_bytes")]
+    pub pieces: Vec<u8>,
+    pub length: Option<u64>,
+    pub files: Option<Vec<FileEntry>>,
+    pub private: Option<u8>,
+}
+
+impl Info {
+    /// Checks the piece layout is usable: a non-zero piece length no larger than
+    /// `MAX_PIECE_LENGTH`, a total size that fits a `u64`, and exactly one 20-byte hash per
+    /// piece of the payload.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.piece_length == 0 || self.piece_length > MAX_PIECE_LENGTH {
+            return Err(format!(
+                "Error parsing TorrentFile, invalid piece length {}.",
+                self.piece_length
+            ));
+        }
+
+        let total_length = match &self.files {
+            Some(files) => files
+                .iter()
+                .try_fold(0u64, |total, file| total.checked_add(file.length)),
+            None => self.length,
+        }
+        .ok_or(String::from(
+            "Error parsing TorrentFile, total length is missing or too large.",
+        ))?;
+
+        let expected = total_length.div_ceil(self.piece_length);
+
+        if expected.checked_mul(20) != Some(self.pieces.len() as u64) {
+            return Err(format!(
+                "Error parsing TorrentFile, expected {} piece hashes but pieces is {} bytes long.",
+                expected,
+                self.pieces.len()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Total payload size: `length` for single-file torrents, the sum of all entries otherwise.
+    /// Saturates on torrents that failed `validate`.
+    pub fn total_length(&self) -> u64 {
+        match &self.files {
+            Some(files) => files
+                .iter()
+                .fold(0, |total: u64, file| total.saturating_add(file.length)),
+            None => self.length.unwrap_or(0),
+        }
+    }
+
+    /// The SHA-1 of every piece, split out of the concatenated `pieces` string.
+    pub fn piece_hashes(&self) -> Vec<[u8; 20]> {
+        self.pieces
+            .chunks_exact(20)
+            .map(|hash| hash.try_into().unwrap())
+            .collect()
+    }
+
+    /// Every file of the torrent placed under `base`, paired with its length, in torrent order.
+    /// Single-file torrents are stored as `base/name`, multi-file ones under `base/name/`.
+    pub fn file_paths(&self, base: &Path) -> Result<Vec<(PathBuf, u64)>, String> {
+        let root = base.join(safe_component(&self.name)?);
+
+        match (&self.files, self.length) {
+            (Some(files), _) => files
+                .iter()
+                .map(|file| Ok((root.join(file.relative_path()?), file.length)))
+                .collect(),
+            (None, Some(length)) => Ok(vec![(root, length)]),
+            (None, None) => Err(String::from(
+                "Error parsing Info, neither length nor files present.",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FileEntry {
+    pub length: u64,
+    pub path: Vec<String>,
+}
+
+impl FileEntry {
+    /// Joins the path components, rejecting anything that could escape the download directory.
+    pub fn relative_path(&self) -> Result<PathBuf, String> {
+        if self.path.is_empty() {
+            return Err(String::from("Error parsing FileEntry, empty path."));
+        }
+
+        self.path
+            .iter()
+            .map(|component| safe_component(component))
+            .collect()
+    }
+}
+
+fn safe_component(component: &str) -> Result<&str, String> {
+    let mut components = Path::new(component).components();
+
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(component),
+        _ => Err(format!("Unsafe path component {:?} in torrent.", component)),
+    }
+}
+
+fn string_or_list<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+
+    Ok(match StringOrList::deserialize(deserializer)? {
+        StringOrList::String(url) if url.is_empty() => None,
+        StringOrList::String(url) => Some(vec![url]),
+        StringOrList::List(urls) => Some(urls),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn torrent_bytes(before: &[u8], info: &[u8], after: &[u8]) -> Vec<u8> {
+        [
+            &b"d8:announce14:http://tracker"[..],
+            before,
+            b"4:info",
+            info,
+            after,
+            b"e",
+        ]
+        .concat()
+    }
+
+    fn single_file_info() -> Vec<u8> {
+        [
+            &b"d6:lengthi5e4:name4:file12:piece lengthi4e6:pieces40:"[..],
+            &[7; 40],
+            b"e",
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn round_trips_through_serde() {
+        let input = torrent_bytes(
+            b"7:comment2:hi10:created by4:test13:creation datei1700000000e",
+            &single_file_info(),
+            b"8:url-listl9:http://wse",
+        );
+        let torrent = TorrentFile::from_bytes(&input).unwrap();
+
+        assert_eq!(torrent.announce, "http://tracker");
+        assert_eq!(torrent.comment.as_deref(), Some("hi"));
+        assert_eq!(torrent.creation_date, Some(1700000000));
+        assert_eq!(torrent.url_list, Some(vec![String::from("http://ws")]));
+        assert_eq!(torrent.info.total_length(), 5);
+        assert_eq!(torrent.info_raw, single_file_info());
+
+        assert_eq!(bencode::to_bytes(&torrent).unwrap(), input);
+    }
+
+    #[test]
+    fn accepts_a_single_url_list_string() {
+        let torrent = TorrentFile::from_bytes(&torrent_bytes(
+            b"",
+            &single_file_info(),
+            b"8:url-list9:http://ws",
+        ))
+        .unwrap();
+
+        assert_eq!(torrent.url_list, Some(vec![String::from("http://ws")]));
+    }
+
+    fn entry(path: &[&str]) -> FileEntry {
+        FileEntry {
+            length: 1,
+            path: path.iter().map(|component| component.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn joins_safe_relative_paths() {
+        assert_eq!(
+            entry(&["dir", "file.txt"]).relative_path().unwrap(),
+            Path::new("dir").join("file.txt")
+        );
+        assert_eq!(entry(&["..."]).relative_path().unwrap(), Path::new("..."));
+    }
+
+    #[test]
+    fn rejects_escaping_path_components() {
+        for path in [
+            &[][..],
+            &[".."],
+            &["dir", ".."],
+            &["."],
+            &[""],
+            &["/etc"],
+            &["dir/.."],
+            &["a/b"],
+        ] {
+            assert!(entry(path).relative_path().is_err(), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn places_files_under_the_torrent_name() {
+        let mut info = Info {
+            name: String::from("name"),
+            piece_length: 4,
+            pieces: vec![],
+            length: None,
+            files: Some(vec![entry(&["a"]), entry(&["b", "c"])]),
+            private: None,
+        };
+
+        assert_eq!(
+            info.file_paths(Path::new("base")).unwrap(),
+            [
+                (PathBuf::from("base/name/a"), 1),
+                (PathBuf::from("base/name/b/c"), 1),
+            ]
+        );
+
+        info.files = Some(vec![entry(&["..", "escape"])]);
+        assert!(info.file_paths(Path::new("base")).is_err());
+
+        info.name = String::from("..");
+        info.files = None;
+        info.length = Some(1);
+        assert!(info.file_paths(Path::new("base")).is_err());
+    }
+
+    fn info(piece_length: u64, pieces: usize, length: Option<u64>) -> Info {
+        Info {
+            name: String::from("name"),
+            piece_length,
+            pieces: vec![0; pieces],
+            length,
+            files: None,
+            private: None,
+        }
+    }
+
+    #[test]
+    fn validates_the_piece_layout() {
+        assert!(info(4, 40, Some(5)).validate().is_ok());
+        assert!(info(4, 20, Some(4)).validate().is_ok());
+        assert!(info(4, 0, Some(0)).validate().is_ok());
+
+        assert!(info(0, 40, Some(5)).validate().is_err());
+        assert!(info(MAX_PIECE_LENGTH * 2, 20, Some(5)).validate().is_err());
+        assert!(info(4, 20, Some(5)).validate().is_err());
+        assert!(info(4, 60, Some(5)).validate().is_err());
+        assert!(info(4, 0, None).validate().is_err());
+    }
+
+    #[test]
+    fn rejects_overflowing_lengths() {
+        // `pieces` would have to be 20 * i64::MAX bytes long.
+        assert!(info(1, 20, Some(i64::MAX as u64)).validate().is_err());
+
+        let mut info = info(MAX_PIECE_LENGTH, 20, None);
+        info.files = Some(vec![
+            FileEntry {
+                length: u64::MAX,
+                path: vec![String::from("a")],
+            },
+            FileEntry {
+                length: u64::MAX,
+                path: vec![String::from("b")],
+            },
+        ]);
+
+        assert!(info.validate().is_err());
+        assert_eq!(info.total_length(), u64::MAX);
+
+        let input = [
+            &b"d6:lengthi9223372036854775807e4:name1:a12:piece lengthi1e6:pieces20:"[..],
+            &[0; 20],
+            b"e",
+        ]
+        .concat();
+        assert!(TorrentFile::from_info(String::new(), input).is_err());
+    }
+}
@@ -1,10 +1,56 @@
-use std::ffi::os_str::Display;
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
 
-use reqwest::Error;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 
-use crate::bencode::{Bencode, BencodedDictionary};
+use crate::{
+    bencode::{self, Bencode, BencodeError, BencodeRef},
+    udp_tracker::UdpTracker,
+};
 
-enum Event {
+mod error;
+
+pub use error::TrackerError;
+
+/// Retransmissions allowed per UDP request before moving on to the next tracker.
+const UDP_MAX_RETRIES: u32 = 2;
+/// First delay after a failure; it doubles with every further consecutive failure.
+const BACKOFF_BASE: Duration = Duration::from_secs(15);
+const BACKOFF_MAX: Duration = Duration::from_secs(30 * 60);
+/// Covers connecting, sending and reading the whole response of an HTTP tracker request.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Shared by every HTTP announce and scrape so connections are pooled and none can hang.
+static HTTP_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .timeout(HTTP_TIMEOUT)
+        .build()
+        .unwrap_or_default()
+});
+
+/// Sends a GET to a tracker and returns the body of a successful response.
+async fn http_get(url: String) -> Result<Vec<u8>, TrackerError> {
+    let response = HTTP_CLIENT.get(url).send().await?.error_for_status()?;
+
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// Exponential backoff after `failures` consecutive failures: 15s, 30s, 60s, ... up to 30 minutes.
+pub fn backoff(failures: u32) -> Duration {
+    BACKOFF_BASE
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(BACKOFF_MAX)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
     Started,
     Stopped,
     Completed,
@@ -20,137 +66,808 @@ impl Event {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "RawPeer", into = "RawPeer")]
 pub struct Peer {
-    peer_id: String,
-    pub ip: String,
-    pub port: u64,
+    pub peer_id: Option<Vec<u8>>,
+    pub address: SocketAddr,
+}
+
+/// Dictionary-model peer (`peer id`, `ip`, `port`) as trackers send it.
+#[derive(Deserialize, Serialize)]
+struct RawPeer {
+    #[serde(
+        rename = "peer id",
+        default,
+        with = "serde_bytes",
+        skip_serializing_if = "Option::is_none"
+    )]
+    peer_id: Option<Vec<u8>>,
+    ip: String,
+    port: u64,
 }
 
-impl TryFrom<BencodedDictionary> for Peer {
+impl TryFrom<RawPeer> for Peer {
     type Error = String;
 
-    fn try_from(value: BencodedDictionary) -> Result<Self, Self::Error> {
-        if !value.contains_key("peer id")
-            || !value.contains_key("ip")
-            || !value.contains_key("port")
-        {
-            return Err(String::from("Error parsing Peer, not valid."));
+    fn try_from(raw: RawPeer) -> Result<Self, Self::Error> {
+        let ip = raw
+            .ip
+            .parse::<IpAddr>()
+            .map_err(|_| format!("Peer ip {} is not an IP address.", raw.ip))?;
+        let port =
+            u16::try_from(raw.port).map_err(|_| format!("Invalid peer port {}.", raw.port))?;
+
+        Ok(Self {
+            peer_id: raw.peer_id,
+            address: SocketAddr::new(ip.to_canonical(), port),
+        })
+    }
+}
+
+impl From<Peer> for RawPeer {
+    fn from(peer: Peer) -> Self {
+        Self {
+            peer_id: peer.peer_id,
+            ip: peer.address.ip().to_string(),
+            port: peer.address.port() as u64,
         }
+    }
+}
 
-        Ok(Peer {
-            peer_id: value.get("peer id").unwrap().try_into_string()?,
-            ip: value.get("ip").unwrap().try_into_string()?,
-            port: value.get("port").unwrap().try_into_int()?,
+impl Peer {
+    /// Decodes one compact peer: a 4-byte IPv4 (BEP 23) or 16-byte IPv6 (BEP 7) address
+    /// followed by a 2-byte port, all in network byte order.
+    pub fn from_compact(entry: &[u8]) -> Option<Self> {
+        let (ip, port) = entry.split_at_checked(entry.len().checked_sub(2)?)?;
+
+        let ip = match ip.len() {
+            4 => IpAddr::from(<[u8; 4]>::try_from(ip).ok()?),
+            16 => IpAddr::from(<[u8; 16]>::try_from(ip).ok()?).to_canonical(),
+            _ => return None,
+        };
+
+        Some(Self {
+            peer_id: None,
+            address: SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])),
         })
     }
+
+    /// Inverse of `from_compact`: 6 bytes for IPv4 peers, 18 for IPv6 ones.
+    pub fn to_compact(&self) -> Vec<u8> {
+        let mut entry = match self.address.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+
+        entry.extend_from_slice(&self.address.port().to_be_bytes());
+        entry
+    }
 }
 
-#[derive(Debug)]
+/// Which IP versions peers are connected over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+    #[default]
+    Any,
+    PreferV4,
+    PreferV6,
+    OnlyV4,
+    OnlyV6,
+}
+
+impl AddressFamily {
+    /// Drops peers of a disabled family and moves those of the preferred one to the front.
+    pub fn apply(self, mut peers: Vec<Peer>) -> Vec<Peer> {
+        match self {
+            Self::Any => {}
+            Self::PreferV4 => peers.sort_by_key(|peer| peer.address.is_ipv6()),
+            Self::PreferV6 => peers.sort_by_key(|peer| peer.address.is_ipv4()),
+            Self::OnlyV4 => peers.retain(|peer| peer.address.is_ipv4()),
+            Self::OnlyV6 => peers.retain(|peer| peer.address.is_ipv6()),
+        }
+
+        peers
+    }
+}
+
+impl FromStr for AddressFamily {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "any" => Ok(Self::Any),
+            "prefer-v4" => Ok(Self::PreferV4),
+            "prefer-v6" => Ok(Self::PreferV6),
+            "v4" => Ok(Self::OnlyV4),
+            "v6" => Ok(Self::OnlyV6),
+            _ => Err(format!("Unknown address family {}.", value)),
+        }
+    }
+}
+
+/// Splits a compact peer string into `entry_size`-byte peers.
+pub(crate) fn parse_compact_peers(bytes: &[u8], entry_size: usize) -> Result<Vec<Peer>, String> {
+    if !bytes.len().is_multiple_of(entry_size) {
+        return Err(format!(
+            "Compact peer list of {} bytes is not a multiple of {}.",
+            bytes.len(),
+            entry_size
+        ));
+    }
+
+    bytes
+        .chunks_exact(entry_size)
+        .map(|entry| Peer::from_compact(entry).ok_or(String::from("Invalid compact peer.")))
+        .collect()
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(try_from = "RawPeerInfo")]
 pub struct PeerInfo {
     pub interval: u64,
+    #[serde(rename = "min interval", skip_serializing_if = "Option::is_none")]
+    pub min_interval: Option<u64>,
+    #[serde(rename = "tracker id", skip_serializing_if = "Option::is_none")]
+    pub tracker_id: Option<String>,
+    #[serde(rename = "warning message", skip_serializing_if = "Option::is_none")]
+    pub warning_message: Option<String>,
+    /// Seeders in the swarm, when the tracker reports it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub complete: Option<u64>,
+    /// Leechers in the swarm, when the tracker reports it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub incomplete: Option<u64>,
     pub peers: Vec<Peer>,
 }
 
-impl TryFrom<BencodedDictionary> for PeerInfo {
+/// Announce response as sent on the wire: `peers` is either a list of dictionaries or a compact
+/// string (BEP 23), and IPv6 peers may arrive separately as a compact `peers6` string (BEP 7).
+#[derive(Deserialize)]
+struct RawPeerInfo {
+    interval: u64,
+    #[serde(rename = "min interval")]
+    min_interval: Option<u64>,
+    #[serde(rename = "tracker id")]
+    tracker_id: Option<String>,
+    #[serde(rename = "warning message")]
+    warning_message: Option<String>,
+    complete: Option<u64>,
+    incomplete: Option<u64>,
+    #[serde(default)]
+    peers: PeerList,
+    #[serde(default, with = "serde_bytes")]
+    peers6: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PeerList {
+    Compact(ByteBuf),
+    Dictionary(Vec<RawPeer>),
+}
+
+impl Default for PeerList {
+    fn default() -> Self {
+        Self::Dictionary(vec![])
+    }
+}
+
+impl TryFrom<RawPeerInfo> for PeerInfo {
     type Error = String;
 
-    fn try_from(value: BencodedDictionary) -> Result<Self, Self::Error> {
-        if !value.contains_key("interval") || !value.contains_key("peers") {
-            return Err(String::from("Error parsing PeerInfo, not valid."));
-        }
+    fn try_from(raw: RawPeerInfo) -> Result<Self, Self::Error> {
+        let mut peers = match raw.peers {
+            PeerList::Compact(bytes) => parse_compact_peers(&bytes, 6)?,
+            // Entries naming a host instead of an address are skipped rather than resolved.
+            PeerList::Dictionary(peers) => peers
+                .into_iter()
+                .filter_map(|peer| Peer::try_from(peer).ok())
+                .collect(),
+        };
 
-        Ok(PeerInfo {
-            interval: value.get("interval").unwrap().try_into_int()?,
-            peers: value
-                .get("peers")
-                .unwrap()
-                .try_into_list()?
-                .iter()
-                .filter_map(|bencoded_peer| match bencoded_peer.try_into_dict() {
-                    Ok((val, _)) => match Peer::try_from(val) {
-                        Ok(v) => Some(v),
-                        Err(_) => None,
-                    },
-                    Err(_) => None,
-                })
-                .collect::<Vec<Peer>>(),
+        peers.extend(parse_compact_peers(&raw.peers6, 18)?);
+
+        Ok(Self {
+            interval: raw.interval,
+            min_interval: raw.min_interval,
+            tracker_id: raw.tracker_id,
+            warning_message: raw.warning_message,
+            complete: raw.complete,
+            incomplete: raw.incomplete,
+            peers,
         })
     }
 }
 
-#[derive(Debug)]
-pub enum TrackerResponse {
-    Failure(String),
-    Success(PeerInfo),
+/// Swarm statistics of one torrent as reported by a scrape. Trackers often leave some of the
+/// counts out, typically `downloaded`; missing ones read as zero.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+pub struct ScrapeStats {
+    #[serde(default)]
+    pub complete: u64,
+    #[serde(default)]
+    pub downloaded: u64,
+    #[serde(default)]
+    pub incomplete: u64,
 }
 
 pub struct TrackerRequest {
-    url: String,
-    info_hash: String,
-    peer_id: String,
-    port: u32,
-    uploaded: String,
-    downloaded: String,
-    left: String,
-    event: Option<Event>,
+    pub(crate) url: String,
+    pub(crate) info_hash: [u8; 20],
+    pub(crate) peer_id: String,
+    pub(crate) port: u32,
+    pub(crate) uploaded: u64,
+    pub(crate) downloaded: u64,
+    pub(crate) left: u64,
+    pub(crate) event: Option<Event>,
+    pub(crate) tracker_id: Option<String>,
+    pub(crate) numwant: Option<u32>,
+    pub(crate) key: u32,
+    pub(crate) ip: Option<IpAddr>,
+    pub(crate) no_peer_id: bool,
 }
 
 impl TrackerRequest {
-    pub fn from(url: String, info_hash: String, peer_id: String, port: u32, left: u64) -> Self {
+    pub fn from(url: String, info_hash: [u8; 20], peer_id: String, port: u32, left: u64) -> Self {
         Self {
             url,
             info_hash,
             peer_id,
             port,
-            left: left.to_string(),
-            uploaded: "0".into(),
-            downloaded: "0".into(),
+            left,
+            uploaded: 0,
+            downloaded: 0,
             event: None,
+            tracker_id: None,
+            numwant: None,
+            key: rand::random(),
+            ip: None,
+            no_peer_id: false,
         }
     }
 
-    pub async fn fetch_peer_info(&self) -> Result<TrackerResponse, Error> {
-        let mut url = format!(
-            "{}?info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}",
-            self.url,
-            self.info_hash,
-            self.peer_id,
-            self.port,
-            self.uploaded,
-            self.downloaded,
-            self.left
-        );
+    /// Number of peers to ask for; trackers pick their own default (usually 50) otherwise.
+    pub fn with_numwant(mut self, numwant: u32) -> Self {
+        self.numwant = Some(numwant);
+        self
+    }
+
+    /// Replaces the random key that lets a tracker recognise us across IP address changes.
+    pub fn with_key(mut self, key: u32) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Address to announce instead of the one the request comes from.
+    pub fn with_ip(mut self, ip: IpAddr) -> Self {
+        self.ip = Some(ip);
+        self
+    }
+
+    /// Asks for peer lists without peer ids; ignored by trackers that answer compactly.
+    pub fn with_no_peer_id(mut self, no_peer_id: bool) -> Self {
+        self.no_peer_id = no_peer_id;
+        self
+    }
+
+    /// Updates the transfer counters and event sent with the next announce.
+    pub fn set_progress(
+        &mut self,
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
+        event: Option<Event>,
+    ) {
+        self.uploaded = uploaded;
+        self.downloaded = downloaded;
+        self.left = left;
+        self.event = event;
+    }
+
+    /// Tracker id a tracker asked to get back on later announces.
+    pub fn set_tracker_id(&mut self, tracker_id: Option<String>) {
+        self.tracker_id = tracker_id;
+    }
+
+    pub async fn fetch_peer_info(&self) -> Result<PeerInfo, TrackerError> {
+        self.fetch_peer_info_from(&self.url).await
+    }
+
+    /// Sends this announce to `url` instead of the request's own tracker, so one request can be
+    /// tried against every tracker of a `TrackerTiers` list.
+    ///
+    /// `udp://` trackers are announced to over BEP 15, everything else over HTTP.
+    pub async fn fetch_peer_info_from(&self, url: &str) -> Result<PeerInfo, TrackerError> {
+        if url.starts_with("udp://") {
+            return UdpTracker::new(url)
+                .await?
+                .with_max_retries(UDP_MAX_RETRIES)
+                .announce(self)
+                .await;
+        }
+
+        let mut query = vec![
+            format!("info_hash={}", percent_encode(&self.info_hash)),
+            format!("peer_id={}", percent_encode(self.peer_id.as_bytes())),
+            format!("port={}", self.port),
+            format!("uploaded={}", self.uploaded),
+            format!("downloaded={}", self.downloaded),
+            format!("left={}", self.left),
+            String::from("compact=1"),
+            format!("key={:08X}", self.key),
+        ];
 
         if let Some(event) = &self.event {
-            url.push_str(&format!("&event={}", event.to_string()));
+            query.push(format!("event={}", event.to_string()));
         }
 
-        let response = reqwest::get(url).await?.bytes().await?;
+        if let Some(numwant) = self.numwant {
+            query.push(format!("numwant={}", numwant));
+        }
 
-        let decoded_response = Bencode::decode_dict(
-            response
-                .iter()
-                .map(|byte| byte.clone())
-                .collect::<Vec<u8>>(),
-        );
+        if let Some(ip) = self.ip {
+            query.push(format!("ip={}", percent_encode(ip.to_string().as_bytes())));
+        }
 
-        if decoded_response.contains_key("failure reason") {
-            return Ok(TrackerResponse::Failure(
-                match decoded_response
-                    .get("failure reason")
-                    .unwrap()
-                    .try_into_string()
-                {
-                    Ok(val) => val,
-                    Err(_) => "Failed to parse TrackerResponse.".to_string(),
-                },
+        if self.no_peer_id {
+            query.push(String::from("no_peer_id=1"));
+        }
+
+        if let Some(tracker_id) = &self.tracker_id {
+            query.push(format!(
+                "trackerid={}",
+                percent_encode(tracker_id.as_bytes())
             ));
         }
 
-        Ok(match PeerInfo::try_from(decoded_response) {
-            Ok(parsed_info) => TrackerResponse::Success(parsed_info),
-            Err(err) => TrackerResponse::Failure(err),
+        let url = append_query(url, &query.join("&"));
+
+        let response = http_get(url).await?;
+        let decoded_response = decode_response(&response)?;
+
+        if decoded_response.get(b"interval").is_none() {
+            return Err(TrackerError::MissingField("interval"));
+        }
+
+        if decoded_response.get(b"peers").is_none() && decoded_response.get(b"peers6").is_none() {
+            return Err(TrackerError::MissingField("peers"));
+        }
+
+        Ok(bencode::from_ref::<PeerInfo>(decoded_response)?)
+    }
+}
+
+/// Decodes an HTTP tracker response, turning a `failure reason` into an error.
+fn decode_response(response: &[u8]) -> Result<BencodeRef<'_>, TrackerError> {
+    let decoded_response = Bencode::decode_ref(response)?;
+
+    if decoded_response.as_dict().is_none() {
+        return Err(TrackerError::Bencode(BencodeError::Custom(String::from(
+            "response is not a dictionary",
+        ))));
+    }
+
+    if let Some(reason) = decoded_response.get(b"failure reason") {
+        return Err(TrackerError::Failure(match reason.as_bytes() {
+            Some(val) => String::from_utf8_lossy(val).into_owned(),
+            None => "Failed to parse the failure reason.".to_string(),
+        }));
+    }
+
+    Ok(decoded_response)
+}
+
+/// Percent-encodes every byte outside the RFC 3986 unreserved set, which is what trackers
+/// expect for the binary `info_hash` and `peer_id`.
+pub fn percent_encode(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
         })
+        .collect()
+}
+
+/// Inverse of `percent_encode`; with `plus_as_space`, `+` decodes to a space as in form-encoded
+/// query strings. `None` if a `%` isn't followed by two hex digits.
+pub fn percent_decode(value: &str, plus_as_space: bool) -> Option<Vec<u8>> {
+    let input = value.as_bytes();
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut index = 0;
+
+    while index < input.len() {
+        match input[index] {
+            b'%' => {
+                let hex = value
+                    .get(index + 1..index + 3)
+                    .filter(|hex| hex.bytes().all(|byte| byte.is_ascii_hexdigit()))?;
+
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                index += 3;
+            }
+            b'+' if plus_as_space => {
+                bytes.push(b' ');
+                index += 1;
+            }
+            byte => {
+                bytes.push(byte);
+                index += 1;
+            }
+        }
+    }
+
+    Some(bytes)
+}
+
+/// Appends `query` to a URL that may already carry a query string of its own (private
+/// trackers often put a passkey there).
+fn append_query(url: &str, query: &str) -> String {
+    if !url.contains('?') {
+        format!("{}?{}", url, query)
+    } else if url.ends_with(['?', '&']) {
+        format!("{}{}", url, query)
+    } else {
+        format!("{}&{}", url, query)
+    }
+}
+
+/// Derives the scrape URL from an announce URL by the BEP 48 convention: the last path segment
+/// must start with `announce`, which is replaced by `scrape`. Other trackers can't be scraped.
+pub fn scrape_url(announce: &str) -> Option<String> {
+    let (path, query) = match announce.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (announce, None),
+    };
+
+    let (base, segment) = path.rsplit_once('/')?;
+    let rest = segment.strip_prefix("announce")?;
+
+    Some(match query {
+        Some(query) => format!("{}/scrape{}?{}", base, rest, query),
+        None => format!("{}/scrape{}", base, rest),
+    })
+}
+
+#[derive(Deserialize, Serialize)]
+pub(crate) struct ScrapeResponse {
+    pub(crate) files: HashMap<ByteBuf, ScrapeStats>,
+}
+
+/// Scrapes `info_hashes` in a single request. Trackers only report torrents they know, so the
+/// result may be missing some of the hashes.
+pub async fn scrape(
+    announce: &str,
+    info_hashes: &[[u8; 20]],
+) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+    if announce.starts_with("udp://") {
+        let mut tracker = UdpTracker::new(announce)
+            .await?
+            .with_max_retries(UDP_MAX_RETRIES);
+        let stats = tracker.scrape(info_hashes).await?;
+
+        return Ok(info_hashes.iter().copied().zip(stats).collect());
+    }
+
+    let query = info_hashes
+        .iter()
+        .map(|info_hash| format!("info_hash={}", percent_encode(info_hash)))
+        .collect::<Vec<String>>()
+        .join("&");
+
+    let url = append_query(
+        &scrape_url(announce).ok_or(TrackerError::InvalidRequest(format!(
+            "{} does not support scraping",
+            announce
+        )))?,
+        &query,
+    );
+
+    let response = http_get(url).await?;
+    let decoded_response = decode_response(&response)?;
+
+    if decoded_response.get(b"files").is_none() {
+        return Err(TrackerError::MissingField("files"));
+    }
+
+    Ok(bencode::from_ref::<ScrapeResponse>(decoded_response)?
+        .files
+        .into_iter()
+        .filter_map(|(info_hash, stats)| Some((info_hash.into_vec().try_into().ok()?, stats)))
+        .collect())
+}
+
+/// How a tracker has been doing lately. Trackers that keep failing are skipped until their
+/// backoff runs out.
+#[derive(Debug, Clone, Default)]
+pub struct TrackerHealth {
+    failures: u32,
+    retry_at: Option<Instant>,
+    last_error: Option<TrackerError>,
+    last_success: Option<Instant>,
+}
+
+impl TrackerHealth {
+    /// Consecutive failures since the last successful announce.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn last_error(&self) -> Option<&TrackerError> {
+        self.last_error.as_ref()
+    }
+
+    pub fn last_success(&self) -> Option<Instant> {
+        self.last_success
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.retry_at.is_none_or(|at| Instant::now() >= at)
+    }
+
+    fn record_success(&mut self) {
+        self.failures = 0;
+        self.retry_at = None;
+        self.last_success = Some(Instant::now());
+    }
+
+    fn record_failure(&mut self, error: TrackerError) {
+        self.failures += 1;
+        self.retry_at = Some(Instant::now() + backoff(self.failures));
+        self.last_error = Some(error);
+    }
+}
+
+/// Tracker list of a torrent, grouped in tiers as described by BEP 12.
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+    health: HashMap<String, TrackerHealth>,
+    /// UDP trackers are kept around between announces so their connection id is reused.
+    udp: HashMap<String, UdpTracker>,
+}
+
+impl TrackerTiers {
+    /// Uses `announce-list` when present (ignoring `announce`, as BEP 12 requires), otherwise a
+    /// single tier holding `announce`. Each tier is shuffled once up front.
+    pub fn new(announce: &str, announce_list: Option<&Vec<Vec<String>>>) -> Self {
+        let mut tiers = match announce_list {
+            Some(list) => list
+                .iter()
+                .map(|tier| {
+                    tier.iter()
+                        .filter(|url| !url.is_empty())
+                        .cloned()
+                        .collect::<Vec<String>>()
+                })
+                .filter(|tier| !tier.is_empty())
+                .collect::<Vec<Vec<String>>>(),
+            None => vec![],
+        };
+
+        if tiers.is_empty() && !announce.is_empty() {
+            tiers.push(vec![announce.to_string()]);
+        }
+
+        let mut rng = rand::rng();
+        for tier in tiers.iter_mut() {
+            tier.shuffle(&mut rng);
+        }
+
+        Self {
+            tiers,
+            health: HashMap::new(),
+            udp: HashMap::new(),
+        }
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// Health of a tracker; `None` until it has been announced to.
+    pub fn health(&self, url: &str) -> Option<&TrackerHealth> {
+        self.health.get(url)
+    }
+
+    /// Moves a tracker that answered to the front of its tier so it is tried first next time.
+    pub fn promote(&mut self, tier: usize, index: usize) {
+        if let Some(tier) = self.tiers.get_mut(tier)
+            && index < tier.len()
+        {
+            let url = tier.remove(index);
+            tier.insert(0, url);
+        }
+    }
+
+    /// Tries every tracker tier by tier until one returns peers, promoting it on success.
+    /// Trackers still backing off from earlier failures are skipped. If none answers, the last
+    /// error is returned.
+    pub async fn announce(&mut self, request: &TrackerRequest) -> Result<PeerInfo, TrackerError> {
+        let mut last = Err(TrackerError::NoTrackers);
+
+        for tier in 0..self.tiers.len() {
+            for index in 0..self.tiers[tier].len() {
+                let url = self.tiers[tier][index].clone();
+                let health = self.health.entry(url.clone()).or_default();
+
+                if !health.is_available() {
+                    if let Some(error) = health.last_error() {
+                        last = Err(error.clone());
+                    }
+
+                    continue;
+                }
+
+                let result = if url.starts_with("udp://") {
+                    self.announce_udp(&url, request).await
+                } else {
+                    request.fetch_peer_info_from(&url).await
+                };
+
+                let health = self.health.entry(url).or_default();
+
+                match result {
+                    Ok(peer_info) => {
+                        health.record_success();
+                        self.promote(tier, index);
+
+                        return Ok(peer_info);
+                    }
+                    Err(err) => {
+                        health.record_failure(err.clone());
+                        last = Err(err);
+                    }
+                }
+            }
+        }
+
+        last
+    }
+
+    async fn announce_udp(
+        &mut self,
+        url: &str,
+        request: &TrackerRequest,
+    ) -> Result<PeerInfo, TrackerError> {
+        let tracker = match self.udp.entry(url.to_string()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(
+                UdpTracker::new(url)
+                    .await?
+                    .with_max_retries(UDP_MAX_RETRIES),
+            ),
+        };
+
+        tracker.announce(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_compact_peers() {
+        let peers =
+            parse_compact_peers(&[10, 0, 0, 1, 0x1a, 0xe1, 127, 0, 0, 1, 0, 80], 6).unwrap();
+
+        assert_eq!(
+            peers
+                .iter()
+                .map(|peer| peer.address.to_string())
+                .collect::<Vec<_>>(),
+            ["10.0.0.1:6881", "127.0.0.1:80"]
+        );
+
+        let mut entry = [0u8; 18];
+        entry[15] = 1;
+        entry[17] = 80;
+
+        let peers = parse_compact_peers(&entry, 18).unwrap();
+        assert_eq!(peers[0].address.to_string(), "[::1]:80");
+
+        assert!(parse_compact_peers(&[], 6).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_truncated_compact_peers() {
+        assert!(parse_compact_peers(&[10, 0, 0, 1, 0x1a], 6).is_err());
+        assert!(parse_compact_peers(&[10, 0, 0, 1, 0x1a, 0xe1, 10], 6).is_err());
+        assert!(parse_compact_peers(&[0; 17], 18).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_entry_sizes() {
+        assert!(parse_compact_peers(&[0; 7], 7).is_err());
+        assert!(parse_compact_peers(&[0; 2], 2).is_err());
+        assert!(parse_compact_peers(&[0; 1], 1).is_err());
+    }
+
+    #[test]
+    fn derives_scrape_urls() {
+        for (announce, scrape) in [
+            ("http://t.example/announce", "http://t.example/scrape"),
+            (
+                "http://t.example/x/announce.php",
+                "http://t.example/x/scrape.php",
+            ),
+            (
+                "http://t.example/announce?passkey=abc",
+                "http://t.example/scrape?passkey=abc",
+            ),
+            ("udp://t.example:80/announce", "udp://t.example:80/scrape"),
+        ] {
+            assert_eq!(scrape_url(announce).as_deref(), Some(scrape));
+        }
+
+        for announce in [
+            "http://t.example/a",
+            "http://t.example/announce/x",
+            "http://t.example/x?path=/announce",
+            "announce",
+        ] {
+            assert_eq!(scrape_url(announce), None, "{}", announce);
+        }
+    }
+
+    #[test]
+    fn percent_encodes_everything_but_unreserved_bytes() {
+        assert_eq!(percent_encode(b"aZ09-._~"), "aZ09-._~");
+        assert_eq!(percent_encode(b" /?&=%+"), "%20%2F%3F%26%3D%25%2B");
+        assert_eq!(percent_encode(&[0x00, 0x12, 0xff]), "%00%12%FF");
+
+        let bytes = (0..=255).collect::<Vec<u8>>();
+        assert_eq!(percent_decode(&percent_encode(&bytes), false), Some(bytes));
+    }
+
+    #[test]
+    fn appends_queries_to_existing_ones() {
+        assert_eq!(
+            append_query("http://t/announce", "a=1"),
+            "http://t/announce?a=1"
+        );
+        assert_eq!(
+            append_query("http://t/announce?passkey=x", "a=1"),
+            "http://t/announce?passkey=x&a=1"
+        );
+        assert_eq!(
+            append_query("http://t/announce?", "a=1"),
+            "http://t/announce?a=1"
+        );
+        assert_eq!(
+            append_query("http://t/announce?passkey=x&", "a=1"),
+            "http://t/announce?passkey=x&a=1"
+        );
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_a_cap() {
+        assert_eq!(backoff(1), Duration::from_secs(15));
+        assert_eq!(backoff(2), Duration::from_secs(30));
+        assert_eq!(backoff(3), Duration::from_secs(60));
+        assert_eq!(backoff(7), Duration::from_secs(960));
+        assert_eq!(backoff(8), BACKOFF_MAX);
+        assert_eq!(backoff(u32::MAX), BACKOFF_MAX);
+    }
+
+    #[test]
+    fn accepts_scrapes_with_missing_counts() {
+        let response: ScrapeResponse = bencode::from_bytes(
+            b"d5:filesd20:aaaaaaaaaaaaaaaaaaaad8:completei5e10:incompletei2eeee",
+        )
+        .unwrap();
+        let stats = response.files[&ByteBuf::from(vec![b'a'; 20])];
+
+        assert_eq!(
+            (stats.complete, stats.downloaded, stats.incomplete),
+            (5, 0, 2)
+        );
     }
 }
@@ -0,0 +1,68 @@
+use std::fmt;
+
+use crate::bencode::BencodeError;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TrackerError {
+    /// The tracker could not be reached, or did not answer in time.
+    Network(String),
+    /// The HTTP tracker answered with a non-success status code.
+    HttpStatus(u16),
+    /// The response is not valid bencode or does not have the expected shape.
+    Bencode(BencodeError),
+    /// A key the protocol requires is absent from the response.
+    MissingField(&'static str),
+    /// The tracker refused the request and said why.
+    Failure(String),
+    /// A UDP tracker sent a truncated or otherwise malformed packet.
+    InvalidResponse(String),
+    /// The request can't be sent, e.g. to an unsupported URL.
+    InvalidRequest(String),
+    /// There was no tracker left to try.
+    NoTrackers,
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Network(message) => write!(f, "network error: {}", message),
+            Self::HttpStatus(status) => write!(f, "tracker answered with HTTP {}", status),
+            Self::Bencode(err) => write!(f, "invalid tracker response: {}", err),
+            Self::MissingField(field) => write!(f, "tracker response is missing `{}`", field),
+            Self::Failure(reason) => write!(f, "tracker failure: {}", reason),
+            Self::InvalidResponse(message) => write!(f, "invalid tracker response: {}", message),
+            Self::InvalidRequest(message) => write!(f, "invalid tracker request: {}", message),
+            Self::NoTrackers => write!(f, "no trackers available"),
+        }
+    }
+}
+
+impl std::error::Error for TrackerError {}
+
+impl From<reqwest::Error> for TrackerError {
+    fn from(value: reqwest::Error) -> Self {
+        match value.status() {
+            Some(status) => Self::HttpStatus(status.as_u16()),
+            None if value.is_timeout() => Self::Network(String::from("request timed out")),
+            None => Self::Network(value.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for TrackerError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Network(value.to_string())
+    }
+}
+
+impl From<BencodeError> for TrackerError {
+    fn from(value: BencodeError) -> Self {
+        Self::Bencode(value)
+    }
+}
+
+impl From<TrackerError> for String {
+    fn from(value: TrackerError) -> Self {
+        value.to_string()
+    }
+}
@@ -0,0 +1,527 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rand::seq::IteratorRandom;
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::timeout,
+};
+
+use crate::{
+    bencode,
+    tracker::{Event, Peer, PeerInfo, ScrapeResponse, ScrapeStats, percent_decode},
+    udp_tracker::{
+        ACTION_ANNOUNCE, ACTION_CONNECT, ACTION_ERROR, ACTION_SCRAPE, CONNECTION_ID_LIFETIME,
+        MAX_PACKET_SIZE, MAX_SCRAPE_HASHES, PROTOCOL_ID, event_from_code, read_u32,
+    },
+};
+
+/*
+ * A small tracker for private swarms and tests. Swarms live in memory keyed by info hash and
+ * are shared by the HTTP (BEP 3/23/48) and UDP (BEP 15) front ends, which can run side by side
+ * on clones of the same `TrackerServer`.
+ */
+
+pub const DEFAULT_INTERVAL: u64 = 30 * 60;
+pub const DEFAULT_MIN_INTERVAL: u64 = 60;
+const DEFAULT_NUMWANT: usize = 50;
+const MAX_NUMWANT: usize = 200;
+const MAX_HTTP_REQUEST_SIZE: usize = 8 * 1024;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+struct Swarm {
+    peers: HashMap<[u8; 20], SwarmPeer>,
+    /// Number of `completed` events seen, reported as `downloaded` by scrapes.
+    downloaded: u64,
+}
+
+#[derive(Debug)]
+struct SwarmPeer {
+    address: SocketAddr,
+    left: u64,
+    last_announce: Instant,
+}
+
+impl Swarm {
+    fn stats(&self) -> ScrapeStats {
+        let complete = self.peers.values().filter(|peer| peer.left == 0).count() as u64;
+
+        ScrapeStats {
+            complete,
+            downloaded: self.downloaded,
+            incomplete: self.peers.len() as u64 - complete,
+        }
+    }
+}
+
+/// One announce, whichever protocol it came in over.
+struct Announce {
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    address: SocketAddr,
+    left: u64,
+    event: Option<Event>,
+    numwant: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackerServer {
+    interval: u64,
+    min_interval: u64,
+    whitelist: Option<HashSet<[u8; 20]>>,
+    swarms: Arc<Mutex<HashMap<[u8; 20], Swarm>>>,
+    /// UDP connection ids handed out, with the address they belong to.
+    connections: Arc<Mutex<HashMap<u64, (SocketAddr, Instant)>>>,
+}
+
+impl Default for TrackerServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TrackerServer {
+    pub fn new() -> Self {
+        Self {
+            interval: DEFAULT_INTERVAL,
+            min_interval: DEFAULT_MIN_INTERVAL,
+            whitelist: None,
+            swarms: Arc::new(Mutex::new(HashMap::new())),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn with_interval(mut self, interval: u64) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Peers announcing again sooner than this (without an event) are turned away.
+    pub fn with_min_interval(mut self, min_interval: u64) -> Self {
+        self.min_interval = min_interval;
+        self
+    }
+
+    /// Only track these torrents; without a whitelist every info hash is accepted.
+    pub fn with_whitelist(mut self, info_hashes: impl IntoIterator<Item = [u8; 20]>) -> Self {
+        self.whitelist = Some(info_hashes.into_iter().collect());
+        self
+    }
+
+    /// Current swarm statistics for `info_hash`, if anyone announced it.
+    pub fn stats(&self, info_hash: &[u8; 20]) -> Option<ScrapeStats> {
+        self.swarms.lock().unwrap().get(info_hash).map(Swarm::stats)
+    }
+
+    /// Records an announce and picks peers for the reply. The error is the failure reason.
+    fn announce(&self, announce: Announce) -> Result<PeerInfo, String> {
+        if let Some(whitelist) = &self.whitelist
+            && !whitelist.contains(&announce.info_hash)
+        {
+            return Err(String::from("Torrent is not registered with this tracker."));
+        }
+
+        let mut swarms = self.swarms.lock().unwrap();
+        let swarm = swarms.entry(announce.info_hash).or_default();
+        let now = Instant::now();
+
+        // Peers that stopped announcing are assumed gone.
+        let expiry = Duration::from_secs(self.interval.saturating_mul(2));
+        swarm
+            .peers
+            .retain(|_, peer| now.duration_since(peer.last_announce) < expiry);
+
+        if let Some(peer) = swarm.peers.get(&announce.peer_id)
+            && announce.event.is_none()
+            && now.duration_since(peer.last_announce) < Duration::from_secs(self.min_interval)
+        {
+            return Err(format!(
+                "Announcing too often; wait at least {} seconds.",
+                self.min_interval
+            ));
+        }
+
+        match announce.event {
+            Some(Event::Stopped) => {
+                swarm.peers.remove(&announce.peer_id);
+            }
+            event => {
+                if event == Some(Event::Completed) {
+                    swarm.downloaded += 1;
+                }
+
+                swarm.peers.insert(
+                    announce.peer_id,
+                    SwarmPeer {
+                        address: announce.address,
+                        left: announce.left,
+                        last_announce: now,
+                    },
+                );
+            }
+        }
+
+        let numwant = announce.numwant.unwrap_or(DEFAULT_NUMWANT).min(MAX_NUMWANT);
+
+        let peers = swarm
+            .peers
+            .iter()
+            .filter(|(peer_id, _)| **peer_id != announce.peer_id)
+            // Seeders have no use for other seeders.
+            .filter(|(_, peer)| announce.left > 0 || peer.left > 0)
+            .choose_multiple(&mut rand::rng(), numwant)
+            .into_iter()
+            .map(|(peer_id, peer)| Peer {
+                peer_id: Some(peer_id.to_vec()),
+                address: peer.address,
+            })
+            .collect();
+
+        let stats = swarm.stats();
+
+        Ok(PeerInfo {
+            interval: self.interval,
+            min_interval: Some(self.min_interval),
+            tracker_id: None,
+            warning_message: None,
+            complete: Some(stats.complete),
+            incomplete: Some(stats.incomplete),
+            peers,
+        })
+    }
+
+    /// Statistics for each requested torrent, or for every torrent when none is named.
+    fn scrape(&self, info_hashes: &[[u8; 20]]) -> HashMap<[u8; 20], ScrapeStats> {
+        let swarms = self.swarms.lock().unwrap();
+
+        if info_hashes.is_empty() {
+            return swarms
+                .iter()
+                .map(|(info_hash, swarm)| (*info_hash, swarm.stats()))
+                .collect();
+        }
+
+        info_hashes
+            .iter()
+            .map(|info_hash| {
+                let stats = swarms.get(info_hash).map(Swarm::stats).unwrap_or_default();
+                (*info_hash, stats)
+            })
+            .collect()
+    }
+
+    pub async fn serve_http(&self, listener: TcpListener) -> std::io::Result<()> {
+        loop {
+            let (stream, address) = listener.accept().await?;
+            let server = self.clone();
+
+            tokio::spawn(async move {
+                let _ = timeout(HTTP_TIMEOUT, server.handle_http(stream, address)).await;
+            });
+        }
+    }
+
+    async fn handle_http(&self, mut stream: TcpStream, address: SocketAddr) -> std::io::Result<()> {
+        let mut request = vec![];
+        let mut buffer = [0u8; 1024];
+
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            let read = stream.read(&mut buffer).await?;
+
+            if read == 0 || request.len() + read > MAX_HTTP_REQUEST_SIZE {
+                return Ok(());
+            }
+
+            request.extend_from_slice(&buffer[..read]);
+        }
+
+        let head = String::from_utf8_lossy(&request);
+        let target = match head.split_whitespace().collect::<Vec<&str>>()[..] {
+            ["GET", target, ..] => target.to_string(),
+            _ => return write_http(&mut stream, "405 Method Not Allowed", b"").await,
+        };
+
+        let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+        let params = parse_query(query);
+
+        let body = if path.ends_with("/announce") {
+            self.http_announce(&params, address)
+        } else if path.ends_with("/scrape") {
+            let info_hashes = params
+                .iter()
+                .filter(|(key, _)| key == "info_hash")
+                .filter_map(|(_, value)| value.as_slice().try_into().ok())
+                .collect::<Vec<[u8; 20]>>();
+
+            bencode::to_bytes(&ScrapeResponse {
+                files: self
+                    .scrape(&info_hashes)
+                    .into_iter()
+                    .map(|(info_hash, stats)| (ByteBuf::from(info_hash.to_vec()), stats))
+                    .collect(),
+            })
+        } else {
+            return write_http(&mut stream, "404 Not Found", b"").await;
+        };
+
+        match body {
+            Ok(body) => write_http(&mut stream, "200 OK", &body).await,
+            Err(_) => write_http(&mut stream, "500 Internal Server Error", b"").await,
+        }
+    }
+
+    fn http_announce(
+        &self,
+        params: &[(String, Vec<u8>)],
+        address: SocketAddr,
+    ) -> Result<Vec<u8>, bencode::BencodeError> {
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_slice())
+        };
+        let number = |name: &str| {
+            param(name)
+                .and_then(|value| std::str::from_utf8(value).ok())
+                .and_then(|value| value.parse::<u64>().ok())
+        };
+
+        let (Some(info_hash), Some(peer_id), Some(port)) = (
+            param("info_hash").and_then(|value| <[u8; 20]>::try_from(value).ok()),
+            param("peer_id").and_then(|value| <[u8; 20]>::try_from(value).ok()),
+            number("port").and_then(|port| u16::try_from(port).ok()),
+        ) else {
+            return failure_response("Missing or invalid info_hash, peer_id or port.");
+        };
+
+        let event = match param("event") {
+            Some(b"started") => Some(Event::Started),
+            Some(b"stopped") => Some(Event::Stopped),
+            Some(b"completed") => Some(Event::Completed),
+            _ => None,
+        };
+
+        let announce = Announce {
+            info_hash,
+            peer_id,
+            address: SocketAddr::new(address.ip().to_canonical(), port),
+            left: number("left").unwrap_or(0),
+            event,
+            numwant: number("numwant").map(|numwant| numwant as usize),
+        };
+
+        let peer_info = match self.announce(announce) {
+            Ok(peer_info) => peer_info,
+            Err(reason) => return failure_response(&reason),
+        };
+
+        if param("compact") == Some(b"0") {
+            return bencode::to_bytes(&peer_info);
+        }
+
+        let (peers, peers6): (Vec<&Peer>, Vec<&Peer>) = peer_info
+            .peers
+            .iter()
+            .partition(|peer| peer.address.is_ipv4());
+
+        bencode::to_bytes(&CompactResponse {
+            interval: peer_info.interval,
+            min_interval: peer_info.min_interval,
+            complete: peer_info.complete,
+            incomplete: peer_info.incomplete,
+            peers: peers.iter().flat_map(|peer| peer.to_compact()).collect(),
+            peers6: peers6.iter().flat_map(|peer| peer.to_compact()).collect(),
+        })
+    }
+
+    pub async fn serve_udp(&self, socket: UdpSocket) -> std::io::Result<()> {
+        let mut buffer = vec![0u8; MAX_PACKET_SIZE];
+
+        loop {
+            let (length, address) = socket.recv_from(&mut buffer).await?;
+
+            if let Some(reply) = self.handle_udp(&buffer[..length], address) {
+                // Clients retransmit when a reply gets lost.
+                let _ = socket.send_to(&reply, address).await;
+            }
+        }
+    }
+
+    fn handle_udp(&self, packet: &[u8], address: SocketAddr) -> Option<Vec<u8>> {
+        if packet.len() < 16 {
+            return None;
+        }
+
+        let connection_id = u64::from_be_bytes(packet[..8].try_into().ok()?);
+        let action = read_u32(packet, 8);
+        let transaction_id = read_u32(packet, 12);
+        let body = &packet[16..];
+
+        let mut reply = vec![];
+        reply.extend_from_slice(&action.to_be_bytes());
+        reply.extend_from_slice(&transaction_id.to_be_bytes());
+
+        if action == ACTION_CONNECT {
+            if connection_id != PROTOCOL_ID {
+                return None;
+            }
+
+            let id = rand::random::<u64>();
+            let mut connections = self.connections.lock().unwrap();
+            connections.retain(|_, (_, issued)| issued.elapsed() < CONNECTION_ID_LIFETIME * 2);
+            connections.insert(id, (address, Instant::now()));
+
+            reply.extend_from_slice(&id.to_be_bytes());
+            return Some(reply);
+        }
+
+        // Clients may keep using an id for a minute; accept it for a little longer than that.
+        let valid = self
+            .connections
+            .lock()
+            .unwrap()
+            .get(&connection_id)
+            .is_some_and(|(owner, issued)| {
+                *owner == address && issued.elapsed() < CONNECTION_ID_LIFETIME * 2
+            });
+
+        if !valid {
+            return Some(udp_error(transaction_id, "Invalid connection id."));
+        }
+
+        match action {
+            ACTION_ANNOUNCE if body.len() >= 82 => {
+                let numwant = read_u32(body, 76) as i32;
+
+                let announce = Announce {
+                    info_hash: body[..20].try_into().ok()?,
+                    peer_id: body[20..40].try_into().ok()?,
+                    address: SocketAddr::new(
+                        address.ip().to_canonical(),
+                        u16::from_be_bytes([body[80], body[81]]),
+                    ),
+                    left: u64::from_be_bytes(body[48..56].try_into().ok()?),
+                    event: event_from_code(read_u32(body, 64)),
+                    numwant: (numwant >= 0).then_some(numwant as usize),
+                };
+
+                match self.announce(announce) {
+                    Ok(peer_info) => {
+                        reply.extend_from_slice(&(peer_info.interval as u32).to_be_bytes());
+                        reply.extend_from_slice(
+                            &(peer_info.incomplete.unwrap_or(0) as u32).to_be_bytes(),
+                        );
+                        reply.extend_from_slice(
+                            &(peer_info.complete.unwrap_or(0) as u32).to_be_bytes(),
+                        );
+
+                        // The reply holds peers of the family the request came in over.
+                        let ipv4 = address.ip().to_canonical().is_ipv4();
+
+                        for peer in peer_info.peers {
+                            if peer.address.is_ipv4() == ipv4 {
+                                reply.extend_from_slice(&peer.to_compact());
+                            }
+                        }
+
+                        Some(reply)
+                    }
+                    Err(reason) => Some(udp_error(transaction_id, &reason)),
+                }
+            }
+            ACTION_SCRAPE => {
+                let info_hashes = body
+                    .chunks_exact(20)
+                    .take(MAX_SCRAPE_HASHES)
+                    .filter_map(|info_hash| info_hash.try_into().ok())
+                    .collect::<Vec<[u8; 20]>>();
+
+                let stats = self.scrape(&info_hashes);
+
+                for info_hash in &info_hashes {
+                    let stats = stats[info_hash];
+
+                    reply.extend_from_slice(&(stats.complete as u32).to_be_bytes());
+                    reply.extend_from_slice(&(stats.downloaded as u32).to_be_bytes());
+                    reply.extend_from_slice(&(stats.incomplete as u32).to_be_bytes());
+                }
+
+                Some(reply)
+            }
+            _ => Some(udp_error(transaction_id, "Unsupported request.")),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CompactResponse {
+    interval: u64,
+    #[serde(rename = "min interval", skip_serializing_if = "Option::is_none")]
+    min_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    complete: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    incomplete: Option<u64>,
+    #[serde(with = "serde_bytes")]
+    peers: Vec<u8>,
+    #[serde(with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
+    peers6: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct FailureResponse<'a> {
+    #[serde(rename = "failure reason")]
+    failure_reason: &'a str,
+}
+
+fn failure_response(reason: &str) -> Result<Vec<u8>, bencode::BencodeError> {
+    bencode::to_bytes(&FailureResponse {
+        failure_reason: reason,
+    })
+}
+
+fn udp_error(transaction_id: u32, message: &str) -> Vec<u8> {
+    let mut reply = vec![];
+    reply.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+    reply.extend_from_slice(&transaction_id.to_be_bytes());
+    reply.extend_from_slice(message.as_bytes());
+    reply
+}
+
+async fn write_http(stream: &mut TcpStream, status: &str, body: &[u8]) -> std::io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await
+}
+
+/// Splits a query string into its percent-decoded parameters; values stay raw bytes since
+/// `info_hash` and `peer_id` are binary. Parameters with a malformed escape are dropped.
+fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+
+            Some((
+                String::from_utf8_lossy(&percent_decode(key, true)?).into_owned(),
+                percent_decode(value, true)?,
+            ))
+        })
+        .collect()
+}
@@ -0,0 +1,286 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::{
+    net::{UdpSocket, lookup_host},
+    time::{Instant, timeout_at},
+};
+
+use crate::tracker::{
+    Event, PeerInfo, ScrapeStats, TrackerError, TrackerRequest, parse_compact_peers,
+};
+
+/*
+ * Client for the UDP tracker protocol (BEP 15). Every request is tied to a connection id the
+ * tracker hands out on `connect`; the id stays valid for a minute and is reused until then.
+ */
+
+pub(crate) const PROTOCOL_ID: u64 = 0x41727101980;
+
+pub(crate) const ACTION_CONNECT: u32 = 0;
+pub(crate) const ACTION_ANNOUNCE: u32 = 1;
+pub(crate) const ACTION_SCRAPE: u32 = 2;
+pub(crate) const ACTION_ERROR: u32 = 3;
+
+pub(crate) const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+/// BEP 15 waits `15 * 2^n` seconds before retransmitting and gives up after `n = 8`.
+const BASE_TIMEOUT_SECS: u64 = 15;
+pub const MAX_RETRIES: u32 = 8;
+/// At most this many info hashes fit in a single scrape packet.
+pub(crate) const MAX_SCRAPE_HASHES: usize = 74;
+pub(crate) const MAX_PACKET_SIZE: usize = 64 * 1024;
+
+pub struct UdpTracker {
+    address: SocketAddr,
+    socket: UdpSocket,
+    connection: Option<(u64, Instant)>,
+    max_retries: u32,
+}
+
+impl UdpTracker {
+    /// Resolves a `udp://host:port[/path]` announce URL and binds a socket for talking to it.
+    pub async fn new(url: &str) -> Result<Self, TrackerError> {
+        let authority = url
+            .strip_prefix("udp://")
+            .ok_or(TrackerError::InvalidRequest(format!(
+                "{} is not a UDP tracker",
+                url
+            )))?
+            .split(['/', '?'])
+            .next()
+            .unwrap_or_default();
+
+        let address = lookup_host(authority)
+            .await?
+            .next()
+            .ok_or(TrackerError::Network(format!(
+                "could not resolve {}",
+                authority
+            )))?;
+
+        let local: SocketAddr = match address {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(address).await?;
+
+        Ok(Self {
+            address,
+            socket,
+            connection: None,
+            max_retries: MAX_RETRIES,
+        })
+    }
+
+    /// Caps the retransmission schedule; the spec's full one takes over an hour to give up.
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries.min(MAX_RETRIES);
+        self
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub async fn announce(&mut self, request: &TrackerRequest) -> Result<PeerInfo, TrackerError> {
+        let peer_id: [u8; 20] = request.peer_id.as_bytes().try_into().map_err(|_| {
+            TrackerError::InvalidRequest(String::from("peer id must be 20 bytes long"))
+        })?;
+
+        let mut payload = Vec::with_capacity(82);
+        payload.extend_from_slice(&request.info_hash);
+        payload.extend_from_slice(&peer_id);
+        payload.extend_from_slice(&request.downloaded.to_be_bytes());
+        payload.extend_from_slice(&request.left.to_be_bytes());
+        payload.extend_from_slice(&request.uploaded.to_be_bytes());
+        payload.extend_from_slice(&event_code(request.event.as_ref()).to_be_bytes());
+        payload.extend_from_slice(&match request.ip {
+            Some(IpAddr::V4(ip)) => ip.octets(),
+            _ => [0; 4],
+        });
+        payload.extend_from_slice(&request.key.to_be_bytes());
+        payload.extend_from_slice(
+            &request
+                .numwant
+                .map_or(-1, |it| it.min(i32::MAX as u32) as i32)
+                .to_be_bytes(),
+        );
+        payload.extend_from_slice(&(request.port as u16).to_be_bytes());
+
+        let (action, body) = self.request(ACTION_ANNOUNCE, &payload).await?;
+
+        match action {
+            ACTION_ANNOUNCE if body.len() >= 12 => {
+                let peer_size = match self.address {
+                    SocketAddr::V4(_) => 6,
+                    SocketAddr::V6(_) => 18,
+                };
+
+                let peers = parse_compact_peers(&body[12..], peer_size)
+                    .map_err(TrackerError::InvalidResponse)?;
+
+                Ok(PeerInfo {
+                    interval: read_u32(&body, 0) as u64,
+                    min_interval: None,
+                    tracker_id: None,
+                    warning_message: None,
+                    incomplete: Some(read_u32(&body, 4) as u64),
+                    complete: Some(read_u32(&body, 8) as u64),
+                    peers,
+                })
+            }
+            ACTION_ERROR => Err(error_message(&body)),
+            _ => Err(TrackerError::InvalidResponse(String::from(
+                "malformed announce response",
+            ))),
+        }
+    }
+
+    /// Returns the swarm statistics of each info hash, in the order they were given.
+    pub async fn scrape(
+        &mut self,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<Vec<ScrapeStats>, TrackerError> {
+        let mut stats = Vec::with_capacity(info_hashes.len());
+
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let (action, body) = self.request(ACTION_SCRAPE, &chunk.concat()).await?;
+
+            match action {
+                ACTION_SCRAPE if body.len() >= chunk.len() * 12 => {
+                    stats.extend(body.chunks_exact(12).take(chunk.len()).map(|entry| {
+                        ScrapeStats {
+                            complete: read_u32(entry, 0) as u64,
+                            downloaded: read_u32(entry, 4) as u64,
+                            incomplete: read_u32(entry, 8) as u64,
+                        }
+                    }));
+                }
+                ACTION_ERROR => return Err(error_message(&body)),
+                _ => {
+                    return Err(TrackerError::InvalidResponse(String::from(
+                        "malformed scrape response",
+                    )));
+                }
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Sends `payload` as `action`, connecting first when there is no fresh connection id.
+    /// Returns the action and body of the reply, retransmitting with the BEP 15 backoff.
+    async fn request(
+        &mut self,
+        action: u32,
+        payload: &[u8],
+    ) -> Result<(u32, Vec<u8>), TrackerError> {
+        for attempt in 0..=self.max_retries {
+            let deadline = Instant::now() + Duration::from_secs(BASE_TIMEOUT_SECS << attempt);
+
+            let connection_id = match self.connection {
+                Some((id, obtained)) if obtained.elapsed() < CONNECTION_ID_LIFETIME => id,
+                _ => match self
+                    .exchange(PROTOCOL_ID, ACTION_CONNECT, &[], deadline)
+                    .await?
+                {
+                    Some((ACTION_CONNECT, body)) if body.len() >= 8 => {
+                        let id = u64::from_be_bytes(body[..8].try_into().unwrap());
+                        self.connection = Some((id, Instant::now()));
+                        id
+                    }
+                    Some((ACTION_ERROR, body)) => return Err(error_message(&body)),
+                    Some(_) => {
+                        return Err(TrackerError::InvalidResponse(String::from(
+                            "malformed connect response",
+                        )));
+                    }
+                    None => continue,
+                },
+            };
+
+            match self
+                .exchange(connection_id, action, payload, deadline)
+                .await?
+            {
+                Some((ACTION_ERROR, body)) => {
+                    // The error may be an expired connection id; don't reuse it.
+                    self.connection = None;
+
+                    return Ok((ACTION_ERROR, body));
+                }
+                Some(reply) => return Ok(reply),
+                None => continue,
+            }
+        }
+
+        Err(TrackerError::Network(format!(
+            "UDP tracker {} did not respond",
+            self.address
+        )))
+    }
+
+    /// Sends one packet and waits until `deadline` for the reply carrying the same transaction
+    /// id. Returns `None` on timeout so the caller can retransmit.
+    async fn exchange(
+        &self,
+        connection_id: u64,
+        action: u32,
+        payload: &[u8],
+        deadline: Instant,
+    ) -> Result<Option<(u32, Vec<u8>)>, TrackerError> {
+        let transaction_id: u32 = rand::random();
+
+        let mut packet = Vec::with_capacity(16 + payload.len());
+        packet.extend_from_slice(&connection_id.to_be_bytes());
+        packet.extend_from_slice(&action.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        packet.extend_from_slice(payload);
+
+        self.socket.send(&packet).await?;
+
+        let mut buffer = vec![0u8; MAX_PACKET_SIZE];
+
+        loop {
+            let length = match timeout_at(deadline, self.socket.recv(&mut buffer)).await {
+                Ok(result) => result?,
+                Err(_) => return Ok(None),
+            };
+
+            // Late replies to an earlier transmission carry a different transaction id.
+            if length >= 8 && read_u32(&buffer, 4) == transaction_id {
+                return Ok(Some((read_u32(&buffer, 0), buffer[8..length].to_vec())));
+            }
+        }
+    }
+}
+
+fn event_code(event: Option<&Event>) -> u32 {
+    match event {
+        None => 0,
+        Some(Event::Completed) => 1,
+        Some(Event::Started) => 2,
+        Some(Event::Stopped) => 3,
+    }
+}
+
+pub(crate) fn event_from_code(code: u32) -> Option<Event> {
+    match code {
+        1 => Some(Event::Completed),
+        2 => Some(Event::Started),
+        3 => Some(Event::Stopped),
+        _ => None,
+    }
+}
+
+fn error_message(body: &[u8]) -> TrackerError {
+    TrackerError::Failure(String::from_utf8_lossy(body).into_owned())
+}
+
+pub(crate) fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}